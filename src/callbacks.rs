@@ -0,0 +1,59 @@
+use crate::error::{AdvanceError, CallbackSnafu};
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+
+/// Callback receiving the reason (or domain) and payload of a yield
+pub type DataCallback = Box<dyn FnMut(u16, &[u8]) -> Result<(), Box<dyn Error>>>;
+
+/// Callback receiving the progress reported by the guest, in thousandths
+pub type ProgressCallback = Box<dyn FnMut(u32)>;
+
+/// Response to a GIO request, as defined by the GIO spec
+///
+/// The machine bindings deliver response codes through the reasons they answer requests with, so
+/// only codes 0 and 1 can reach the guest. Other codes fail the run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GioResponse {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// Handler for the GIO requests of a domain
+///
+/// Handlers are registered by domain and receive the domain and payload of each request.
+pub enum Callback {
+    Sync(Box<dyn Fn(u16, Vec<u8>) -> GioResult>),
+    Async(Box<dyn Fn(u16, Vec<u8>) -> GioFuture>),
+}
+
+pub type GioResult = Result<GioResponse, Box<dyn Error>>;
+
+pub type GioFuture = Pin<Box<dyn Future<Output = GioResult>>>;
+
+/// Every callback a runner serves the machine's yields with
+#[derive(Default)]
+pub(crate) struct Callbacks {
+    pub report: Option<DataCallback>,
+    pub output: Option<DataCallback>,
+    pub finish: Option<DataCallback>,
+    pub progress: Option<ProgressCallback>,
+    /// Callbacks for automatic yields that carry neither outputs nor reports, keyed by domain
+    pub automatic_gio: HashMap<u16, DataCallback>,
+    /// Callbacks for manual GIO requests, keyed by domain
+    pub gio: HashMap<u16, Callback>,
+}
+
+/// Invoke an optional data callback
+pub(crate) fn notify(
+    callback: &mut Option<DataCallback>,
+    reason: u16,
+    data: &[u8],
+) -> Result<(), AdvanceError> {
+    match callback {
+        Some(callback) => callback(reason, data).context(CallbackSnafu),
+        None => Ok(()),
+    }
+}
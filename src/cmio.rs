@@ -0,0 +1,311 @@
+use crate::callbacks::{notify, Callback, Callbacks, GioResponse};
+use crate::console::ConsoleCapture;
+use crate::error::{
+    AdvanceError, CallbackSnafu, ConsoleCaptureSnafu, MachineSnafu, MissingGioCallbackSnafu,
+    OutputsRootHashMismatchSnafu, OutputsTreeSnafu, UnexpectedInitialYieldSnafu,
+    UnsupportedGioResponseCodeSnafu,
+};
+use crate::hash::Hash;
+use crate::limits::{yield_now, RunLimits, RUN_SLICE_CYCLES};
+use crate::outputs::OutputsTree;
+use crate::YieldManualReason;
+use cartesi_machine::{
+    cartesi_machine_sys::{
+        CM_CMIO_YIELD_REASON_ADVANCE_STATE, CM_CMIO_YIELD_REASON_INSPECT_STATE,
+        CM_PMA_CMIO_TX_BUFFER_START, CM_REG_HTIF_TOHOST, CM_REG_IFLAGS_Y,
+    },
+    constants::cmio::{commands, tohost::manual::RX_ACCEPTED},
+    machine::Machine,
+    types::{
+        cmio::{AutomaticReason, CmioRequest, CmioResponseReason, ManualReason},
+        BreakReason,
+    },
+};
+use snafu::{OptionExt, ResultExt};
+
+pub(crate) type ConsoleSink<'a> = Box<dyn FnMut(&[u8]) + 'a>;
+
+/// What the guest produced while handling a request
+#[derive(Default)]
+pub(crate) struct RunRecord {
+    pub outputs: Vec<Vec<u8>>,
+    pub reports: Vec<Vec<u8>>,
+    pub outputs_root_hash: Option<Hash>,
+    pub exception_message: Option<String>,
+    pub gio_round_trips: u64,
+}
+
+/// Check that the machine is waiting for the next request
+pub(crate) fn expect_rx_accepted(machine: &mut Machine) -> Result<(), AdvanceError> {
+    let cmdio = machine.receive_cmio_request().context(MachineSnafu)?;
+    snafu::ensure!(
+        cmdio.reason() == RX_ACCEPTED && cmdio.cmd() == commands::YIELD_MANUAL,
+        UnexpectedInitialYieldSnafu {
+            cmd: cmdio.cmd(),
+            reason: cmdio.reason(),
+        }
+    );
+    Ok(())
+}
+
+/// Hand a request to a machine waiting for one
+pub(crate) fn send_request(
+    machine: &mut Machine,
+    reason: CmioResponseReason,
+    data: &[u8],
+) -> Result<(), AdvanceError> {
+    machine
+        .send_cmio_response(reason, data)
+        .context(MachineSnafu)?;
+    machine.write_reg(CM_REG_IFLAGS_Y, 0).context(MachineSnafu)
+}
+
+/// Run the machine, serving its requests, until it accepts, rejects or raises an exception
+///
+/// Outputs are pushed to `outputs_tree` and forwarded; without a tree, as for inspects, they are
+/// ignored. On acceptance, the outputs root hash reported by the guest is checked against the tree.
+/// Everything the guest produces is also kept in `record`. With a console sink, stdout is captured
+/// while the request runs and handed to it whenever the capture pauses. The limits are checked
+/// between slices of cycles and around GIO callbacks, and waits on async callbacks and on the
+/// capture are abandoned once they're hit.
+pub(crate) async fn run_until_finished(
+    machine: &mut Machine,
+    callbacks: &mut Callbacks,
+    outputs_tree: Option<&mut OutputsTree>,
+    limits: &RunLimits,
+    console_sink: &mut Option<ConsoleSink<'_>>,
+    record: &mut RunRecord,
+) -> Result<YieldManualReason, AdvanceError> {
+    let Some(sink) = console_sink else {
+        return serve_until_finished(machine, callbacks, outputs_tree, limits, None, record).await;
+    };
+    let mut console = CapturedConsole {
+        sink,
+        capture: None,
+    };
+    let result = match console.resume(limits).await {
+        Ok(None) => {
+            let console = Some(&mut console);
+            serve_until_finished(machine, callbacks, outputs_tree, limits, console, record).await
+        }
+        Ok(Some(interruption)) => Ok(interruption),
+        Err(err) => Err(err),
+    };
+    let paused = console.pause();
+    let reason = result?;
+    paused?;
+    Ok(reason)
+}
+
+/// Capture of the console output of a request, paused while other tasks get to print
+struct CapturedConsole<'s, 'a> {
+    sink: &'s mut ConsoleSink<'a>,
+    capture: Option<ConsoleCapture>,
+}
+
+impl CapturedConsole<'_, '_> {
+    /// Wait for other captures to finish and start capturing again
+    ///
+    /// Returns the reason the request was interrupted while waiting, if it was.
+    async fn resume(
+        &mut self,
+        limits: &RunLimits,
+    ) -> Result<Option<YieldManualReason>, AdvanceError> {
+        match limits.interruptible(ConsoleCapture::start()).await {
+            Ok(capture) => {
+                self.capture = Some(capture.context(ConsoleCaptureSnafu)?);
+                Ok(None)
+            }
+            Err(interruption) => Ok(Some(interruption)),
+        }
+    }
+
+    /// Stop capturing and hand what was captured to the sink
+    fn pause(&mut self) -> Result<(), AdvanceError> {
+        if let Some(capture) = self.capture.take() {
+            let output = capture.finish().context(ConsoleCaptureSnafu)?;
+            if !output.is_empty() {
+                (self.sink)(&output);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Body of `run_until_finished`, pausing the console capture around async GIO callbacks
+async fn serve_until_finished(
+    machine: &mut Machine,
+    callbacks: &mut Callbacks,
+    mut outputs_tree: Option<&mut OutputsTree>,
+    limits: &RunLimits,
+    mut console: Option<&mut CapturedConsole<'_, '_>>,
+    record: &mut RunRecord,
+) -> Result<YieldManualReason, AdvanceError> {
+    let mcycle_end = match limits.cycle_limit {
+        Some(cycle_limit) => machine
+            .mcycle()
+            .context(MachineSnafu)?
+            .saturating_add(cycle_limit),
+        None => u64::MAX,
+    };
+    loop {
+        if !machine.iflags_y().context(MachineSnafu)? {
+            if let Some(interrupted) = run_in_slices(machine, mcycle_end, limits).await? {
+                return Ok(interrupted);
+            }
+        }
+        let cmdio = machine.receive_cmio_request().context(MachineSnafu)?;
+        let reason = cmdio.reason();
+        match cmdio {
+            CmioRequest::Automatic(automatic_reason) => match automatic_reason {
+                AutomaticReason::TxReport { data } => {
+                    notify(&mut callbacks.report, reason, &data)?;
+                    record.reports.push(data);
+                }
+                AutomaticReason::TxOutput { data } => {
+                    if let Some(outputs_tree) = outputs_tree.as_deref_mut() {
+                        outputs_tree.push(&data).context(OutputsTreeSnafu)?;
+                        notify(&mut callbacks.output, reason, &data)?;
+                        record.outputs.push(data);
+                    }
+                }
+                AutomaticReason::Progress { mille_progress } => {
+                    if let Some(progress_callback) = &mut callbacks.progress {
+                        progress_callback(mille_progress);
+                    }
+                }
+                _ => match callbacks.automatic_gio.get_mut(&reason) {
+                    Some(gio_callback) => {
+                        let data = read_tx_buffer(machine)?;
+                        gio_callback(reason, &data).context(CallbackSnafu)?;
+                    }
+                    None => {
+                        eprintln!("WARNING: ignoring automatic yield with unknown reason {reason}")
+                    }
+                },
+            },
+            CmioRequest::Manual(manual_reason) => match manual_reason {
+                ManualReason::RxAccepted {
+                    output_hashes_root_hash,
+                } => {
+                    if let Some(outputs_tree) = &outputs_tree {
+                        let reported = Hash::from(output_hashes_root_hash);
+                        snafu::ensure!(
+                            outputs_tree.root_hash() == &reported,
+                            OutputsRootHashMismatchSnafu {
+                                computed: outputs_tree.root_hash().clone(),
+                                reported,
+                            }
+                        );
+                    }
+                    notify(&mut callbacks.finish, reason, &output_hashes_root_hash)?;
+                    record.outputs_root_hash = Some(Hash::from(output_hashes_root_hash));
+                    return Ok(YieldManualReason::Accepted);
+                }
+                ManualReason::RxRejected => {
+                    notify(&mut callbacks.finish, reason, &[])?;
+                    return Ok(YieldManualReason::Rejected);
+                }
+                ManualReason::TxException { message } => {
+                    notify(&mut callbacks.finish, reason, message.as_bytes())?;
+                    record.exception_message = Some(message);
+                    return Ok(YieldManualReason::Exception);
+                }
+                ManualReason::GIO { domain, data } => {
+                    let gio_callback = callbacks
+                        .gio
+                        .get(&domain)
+                        .context(MissingGioCallbackSnafu { domain })?;
+                    let response = match gio_callback {
+                        Callback::Sync(sync_callback) => sync_callback(domain, data),
+                        Callback::Async(async_callback) => {
+                            // Other tasks may print while the callback runs
+                            if let Some(console) = console.as_deref_mut() {
+                                console.pause()?;
+                            }
+                            let response =
+                                match limits.interruptible(async_callback(domain, data)).await {
+                                    Ok(response) => response,
+                                    Err(interruption) => return Ok(interruption),
+                                };
+                            if let Some(console) = console.as_deref_mut() {
+                                if let Some(interruption) = console.resume(limits).await? {
+                                    return Ok(interruption);
+                                }
+                            }
+                            response
+                        }
+                    }
+                    .context(CallbackSnafu)?;
+                    if let Some(interruption) = limits.interruption() {
+                        return Ok(interruption);
+                    }
+                    send_gio_response(machine, &response)?;
+                    record.gio_round_trips += 1;
+                }
+            },
+        };
+        machine
+            .write_reg(CM_REG_IFLAGS_Y, 0)
+            .context(MachineSnafu)?;
+    }
+}
+
+/// Run the machine until it yields, checking the limits between slices of cycles
+///
+/// Control goes back to the executor between slices, so that other tasks, such as one cancelling
+/// the request, get to run even on a single-threaded executor. Returns the reason the run was
+/// interrupted, if it was.
+async fn run_in_slices(
+    machine: &mut Machine,
+    mcycle_end: u64,
+    limits: &RunLimits,
+) -> Result<Option<YieldManualReason>, AdvanceError> {
+    loop {
+        if let Some(interruption) = limits.interruption() {
+            return Ok(Some(interruption));
+        }
+        let slice_end = machine
+            .mcycle()
+            .context(MachineSnafu)?
+            .saturating_add(RUN_SLICE_CYCLES)
+            .min(mcycle_end);
+        let break_reason = machine.run(slice_end).context(MachineSnafu)?;
+        if break_reason != BreakReason::ReachedTargetMcycle {
+            return Ok(None);
+        }
+        if slice_end == mcycle_end {
+            return Ok(Some(YieldManualReason::CycleLimitExceeded));
+        }
+        yield_now().await;
+    }
+}
+
+/// Read the payload of the current yield from the cmio transmit buffer
+fn read_tx_buffer(machine: &mut Machine) -> Result<Vec<u8>, AdvanceError> {
+    let tohost = machine.read_reg(CM_REG_HTIF_TOHOST).context(MachineSnafu)?;
+    let length = tohost & 0xffffffff;
+    machine
+        .read_memory(CM_PMA_CMIO_TX_BUFFER_START, length)
+        .context(MachineSnafu)
+}
+
+/// Send the response to a GIO request back to the guest
+///
+/// The machine bindings only accept the advance and inspect reasons as response codes, so other
+/// codes are rejected.
+fn send_gio_response(machine: &mut Machine, response: &GioResponse) -> Result<(), AdvanceError> {
+    let reason = match response.code as u32 {
+        CM_CMIO_YIELD_REASON_ADVANCE_STATE => CmioResponseReason::Advance,
+        CM_CMIO_YIELD_REASON_INSPECT_STATE => CmioResponseReason::Inspect,
+        _ => {
+            return UnsupportedGioResponseCodeSnafu {
+                code: response.code,
+            }
+            .fail()
+        }
+    };
+    machine
+        .send_cmio_response(reason, &response.data)
+        .context(MachineSnafu)
+}
@@ -0,0 +1,177 @@
+use alloy_primitives::U256;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::pin::Pin;
+use std::sync::{Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread::JoinHandle;
+
+/// Destination of the guest's HTIF console output
+pub enum Console {
+    /// Print to the host's stdout
+    Stdout,
+    /// Discard the output
+    Silent,
+    /// Hand the output to a callback, tagged with the index of the input being processed
+    /// (`None` for inspects)
+    ///
+    /// The emulator writes straight to the process' stdout, so it's redirected while the request
+    /// runs, except while waiting on async GIO callbacks: whatever other threads, tasks and
+    /// callbacks print in the meantime is captured too, and captures of concurrent requests wait
+    /// for each other.
+    Capture(ConsoleCallback),
+}
+
+pub type ConsoleCallback = Box<dyn FnMut(Option<U256>, &[u8])>;
+
+/// The emulator writes console output straight to the process' stdout, so capturing it means
+/// redirecting that file descriptor. Only one capture can be active at a time.
+static STDOUT_REDIRECT: Mutex<RedirectState> = Mutex::new(RedirectState {
+    active: false,
+    waiters: VecDeque::new(),
+});
+
+struct RedirectState {
+    active: bool,
+    /// Tasks waiting for the active capture to finish
+    waiters: VecDeque<Waker>,
+}
+
+fn lock_redirect() -> MutexGuard<'static, RedirectState> {
+    STDOUT_REDIRECT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Redirection of the host's stdout into a pipe drained by a background thread
+pub(crate) struct ConsoleCapture {
+    saved_stdout: Option<OwnedFd>,
+    reader: Option<JoinHandle<io::Result<Vec<u8>>>>,
+}
+
+/// Future returned by `ConsoleCapture::start`
+pub(crate) struct StartCapture;
+
+impl Future for StartCapture {
+    type Output = io::Result<ConsoleCapture>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = lock_redirect();
+        if state.active {
+            if !state
+                .waiters
+                .iter()
+                .any(|waiter| waiter.will_wake(cx.waker()))
+            {
+                state.waiters.push_back(cx.waker().clone());
+            }
+            return Poll::Pending;
+        }
+        state.active = true;
+        drop(state);
+        let mut capture = ConsoleCapture {
+            saved_stdout: None,
+            reader: None,
+        };
+        // Dropping the capture on failure lets the next one start
+        Poll::Ready(capture.redirect().map(|_| capture))
+    }
+}
+
+impl ConsoleCapture {
+    /// Start redirecting stdout once no other capture is active
+    pub fn start() -> StartCapture {
+        StartCapture
+    }
+
+    fn redirect(&mut self) -> io::Result<()> {
+        flush_stdout()?;
+        let mut fds = [0; 2];
+        cvt(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) })?;
+        let (mut read_end, write_end) =
+            unsafe { (File::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        let saved_stdout = unsafe { OwnedFd::from_raw_fd(cvt(libc::dup(libc::STDOUT_FILENO))?) };
+        cvt(unsafe { libc::dup2(write_end.as_raw_fd(), libc::STDOUT_FILENO) })?;
+        // From now on stdout holds the only write end, so restoring it closes the pipe
+        drop(write_end);
+        self.saved_stdout = Some(saved_stdout);
+        self.reader = Some(std::thread::spawn(move || {
+            let mut output = vec![];
+            read_end.read_to_end(&mut output)?;
+            Ok(output)
+        }));
+        Ok(())
+    }
+
+    /// Restore stdout and return everything written to it since the capture started
+    pub fn finish(mut self) -> io::Result<Vec<u8>> {
+        self.restore()?;
+        self.reader
+            .take()
+            .expect("reader is only taken here")
+            .join()
+            .expect("console reader thread panicked")
+    }
+
+    fn restore(&mut self) -> io::Result<()> {
+        if let Some(saved_stdout) = self.saved_stdout.take() {
+            flush_stdout()?;
+            cvt(unsafe { libc::dup2(saved_stdout.as_raw_fd(), libc::STDOUT_FILENO) })?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ConsoleCapture {
+    fn drop(&mut self) {
+        let _ = self.restore();
+        let mut state = lock_redirect();
+        state.active = false;
+        // Waiters may have given up, so all of them get to try again
+        for waiter in state.waiters.drain(..) {
+            waiter.wake();
+        }
+    }
+}
+
+fn flush_stdout() -> io::Result<()> {
+    io::stdout().flush()?;
+    unsafe { libc::fflush(std::ptr::null_mut()) };
+    Ok(())
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_it_captures_writes_to_stdout() {
+        let mut cx = Context::from_waker(Waker::noop());
+        let Poll::Ready(capture) = Pin::new(&mut ConsoleCapture::start()).poll(&mut cx) else {
+            panic!("no other capture is active");
+        };
+        let capture = capture.unwrap();
+        let Poll::Pending = Pin::new(&mut ConsoleCapture::start()).poll(&mut cx) else {
+            panic!("a capture is already active");
+        };
+        let message = b"hello from the guest\n";
+        let written =
+            unsafe { libc::write(libc::STDOUT_FILENO, message.as_ptr().cast(), message.len()) };
+        assert_eq!(written, message.len() as isize);
+        let output = capture.finish().unwrap();
+        assert!(output
+            .windows(message.len())
+            .any(|window| window == message));
+    }
+}
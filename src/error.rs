@@ -0,0 +1,75 @@
+use crate::hash::Hash;
+use crate::merkle_tree;
+use alloy_primitives::U256;
+use cartesi_machine::error::MachineError;
+use snafu::Snafu;
+use std::error::Error;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum AdvanceError {
+    #[snafu(display("failed to load machine snapshot {path}"))]
+    LoadSnapshot { path: String, source: MachineError },
+    #[snafu(display("lambda state I/O failed on {path}"))]
+    LambdaStateIo {
+        path: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("snapshot has no memory range starting at {start:#x}"))]
+    MissingMemoryRange { start: u64 },
+    #[snafu(display("lambda state {path} is {length} bytes long, which isn't page-aligned"))]
+    MisalignedLambdaState { path: String, length: u64 },
+    #[snafu(display(
+        "lambda state {path} is {length} bytes long, but its memory range is {range_length}"
+    ))]
+    LambdaStateSize {
+        path: String,
+        length: u64,
+        range_length: u64,
+    },
+    #[snafu(display("{path} is not a valid lambda state delta"))]
+    InvalidLambdaStateDelta { path: String },
+    #[snafu(display("invalid line in lambda store chain {path}: {line}"))]
+    InvalidLambdaStoreChain { path: String, line: String },
+    #[snafu(display("lambda state {path} can't be cloned onto itself"))]
+    SameLambdaState { path: String },
+    #[snafu(display("input {index} can't be committed after input {latest}"))]
+    StaleLambdaStoreIndex { index: U256, latest: U256 },
+    #[snafu(display("could not reflink lambda state to {path}, and copies are not allowed"))]
+    ReflinkUnavailable {
+        path: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("{path} is not a valid lambda state journal"))]
+    InvalidLambdaStateJournal { path: String },
+    #[snafu(display(
+        "lambda state journal {path} holds an interrupted transition, which must be recovered first"
+    ))]
+    PendingLambdaStateJournal { path: String },
+    #[snafu(display("state busy: {path} is locked by another runner"))]
+    StateBusy { path: String },
+    #[snafu(display("failed to map lambda state into the machine"))]
+    MapLambdaState { source: MachineError },
+    #[snafu(display("failed to store machine snapshot to {path}"))]
+    StoreSnapshot { path: String, source: MachineError },
+    #[snafu(display("machine operation failed"))]
+    Machine { source: MachineError },
+    #[snafu(display("unexpected initial yield (cmd {cmd}, reason {reason})"))]
+    UnexpectedInitialYield { cmd: u8, reason: u16 },
+    #[snafu(display("GIO response code {code} is not supported"))]
+    UnsupportedGioResponseCode { code: u16 },
+    #[snafu(display("no GIO callback registered for domain {domain}"))]
+    MissingGioCallback { domain: u16 },
+    #[snafu(display("failed to capture the console output"))]
+    ConsoleCapture { source: std::io::Error },
+    #[snafu(display("session can't take new requests after failing to revert one"))]
+    SessionUnusable,
+    #[snafu(display("failed to update the outputs merkle tree"))]
+    OutputsTree { source: merkle_tree::Error },
+    #[snafu(display(
+        "outputs root hash reported by the guest ({reported:?}) doesn't match the computed one ({computed:?})"
+    ))]
+    OutputsRootHashMismatch { computed: Hash, reported: Hash },
+    #[snafu(display("callback failed"))]
+    Callback { source: Box<dyn Error> },
+}
@@ -0,0 +1,80 @@
+use alloy_primitives::{Address, U256};
+use alloy_sol_types::{sol, SolCall};
+
+/// Input metadata sent to the guest alongside the payload of an advance request
+///
+/// The fields mirror the arguments of the `EvmAdvance` call from the Cartesi `Inputs` interface.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AdvanceMetadata {
+    pub chain_id: U256,
+    pub app_contract: Address,
+    pub msg_sender: Address,
+    pub block_number: U256,
+    pub block_timestamp: U256,
+    pub prev_randao: U256,
+    pub index: U256,
+}
+
+/// Input of an advance-state request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AdvanceInput {
+    pub metadata: AdvanceMetadata,
+    pub payload: Vec<u8>,
+}
+
+sol! { interface Inputs {
+    function EvmAdvance(
+        uint256 chainId,
+        address appContract,
+        address msgSender,
+        uint256 blockNumber,
+        uint256 blockTimestamp,
+        uint256 prevRandao,
+        uint256 index,
+        bytes calldata payload
+    ) external;
+} }
+
+pub(crate) fn encode_evm_advance(metadata: &AdvanceMetadata, payload: Vec<u8>) -> Vec<u8> {
+    let call = Inputs::EvmAdvanceCall {
+        chainId: metadata.chain_id,
+        appContract: metadata.app_contract,
+        msgSender: metadata.msg_sender,
+        blockNumber: metadata.block_number,
+        blockTimestamp: metadata.block_timestamp,
+        prevRandao: metadata.prev_randao,
+        index: metadata.index,
+        payload: payload.into(),
+    };
+    call.abi_encode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::address;
+
+    #[test]
+    fn test_it_encodes_metadata_into_evm_advance() {
+        let metadata = AdvanceMetadata {
+            chain_id: U256::from(31337),
+            app_contract: address!("0xab7528bb862fb57e8a2bcd567a2e929a0be56a5e"),
+            msg_sender: address!("0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266"),
+            block_number: U256::from(42),
+            block_timestamp: U256::from(1700000000),
+            prev_randao: U256::from(7),
+            index: U256::from(3),
+        };
+        let encoded = encode_evm_advance(&metadata, vec![0xde, 0xad]);
+        assert_eq!(&encoded[..4], Inputs::EvmAdvanceCall::SELECTOR);
+        let call = Inputs::EvmAdvanceCall::abi_decode(&encoded).unwrap();
+        assert_eq!(call.chainId, metadata.chain_id);
+        assert_eq!(call.appContract, metadata.app_contract);
+        assert_eq!(call.msgSender, metadata.msg_sender);
+        assert_eq!(call.blockNumber, metadata.block_number);
+        assert_eq!(call.blockTimestamp, metadata.block_timestamp);
+        assert_eq!(call.prevRandao, metadata.prev_randao);
+        assert_eq!(call.index, metadata.index);
+        assert_eq!(call.payload.as_ref(), &[0xde, 0xad]);
+    }
+}
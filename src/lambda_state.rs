@@ -0,0 +1,597 @@
+use crate::error::{
+    AdvanceError, InvalidLambdaStateDeltaSnafu, LambdaStateIoSnafu, LambdaStateSizeSnafu,
+    MisalignedLambdaStateSnafu, ReflinkUnavailableSnafu, SameLambdaStateSnafu,
+};
+use crate::hash::{Digest, Hash, Hasher};
+use crate::merkle_tree::{complete::Tree, pristine};
+use crate::transition::sync_parent;
+use snafu::{OptionExt, ResultExt};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const LOG2_WORD_SIZE: usize = 3;
+const LOG2_PAGE_SIZE: usize = 12;
+const WORD_SIZE: usize = 1 << LOG2_WORD_SIZE;
+
+/// Size of a machine memory page
+pub const PAGE_SIZE: u64 = 1 << LOG2_PAGE_SIZE;
+
+/// Magic bytes at the start of lambda state delta files
+const DELTA_MAGIC: &[u8; 8] = b"LSDELTA1";
+
+/// Compute the merkle root of a lambda state file the way the machine merkleizes memory
+///
+/// Words are 8 bytes, leaves 32 bytes and pages 4096 bytes. The tree spans the smallest power of
+/// two that holds the file, so its root is the hash of the file's memory range when the range
+/// length is a power of two.
+pub fn merkle_root(path: impl AsRef<Path>) -> Result<Hash, AdvanceError> {
+    let path = path.as_ref();
+    let path_str = path.display().to_string();
+    let file = File::open(path).context(LambdaStateIoSnafu { path: &path_str })?;
+    let length = file
+        .metadata()
+        .context(LambdaStateIoSnafu { path: &path_str })?
+        .len();
+    snafu::ensure!(
+        length % PAGE_SIZE == 0,
+        MisalignedLambdaStateSnafu {
+            path: &path_str,
+            length,
+        }
+    );
+    let pages = length / PAGE_SIZE;
+    let log2_root_size = LOG2_PAGE_SIZE + pages.next_power_of_two().trailing_zeros() as usize;
+
+    let pristine_page = pristine::Tree::new(LOG2_PAGE_SIZE, LOG2_WORD_SIZE).expect("cannot fail");
+    let mut reader = BufReader::new(file);
+    let mut page = vec![0; PAGE_SIZE as usize];
+    let mut page_hashes = vec![];
+    let mut non_pristine_pages = 0;
+    for _ in 0..pages {
+        reader
+            .read_exact(&mut page)
+            .context(LambdaStateIoSnafu { path: &path_str })?;
+        if page.iter().all(|&byte| byte == 0) {
+            page_hashes.push(
+                pristine_page
+                    .get_hash(LOG2_PAGE_SIZE)
+                    .expect("cannot fail")
+                    .clone(),
+            );
+        } else {
+            page_hashes.push(page_hash(&page));
+            non_pristine_pages = page_hashes.len();
+        }
+    }
+    // Trailing pristine pages are left to the tree
+    page_hashes.truncate(non_pristine_pages);
+    let tree = Tree::new_from_leaves(log2_root_size, LOG2_PAGE_SIZE, LOG2_WORD_SIZE, page_hashes)
+        .expect("cannot fail");
+    Ok(tree.get_root_hash().clone())
+}
+
+/// Compute the merkle root of a page from the hashes of its words
+fn page_hash(page: &[u8]) -> Hash {
+    let word_hashes = page
+        .chunks(WORD_SIZE)
+        .map(|word| Hasher::digest(word).into())
+        .collect();
+    let tree = Tree::new_from_leaves(LOG2_PAGE_SIZE, LOG2_WORD_SIZE, LOG2_WORD_SIZE, word_hashes)
+        .expect("cannot fail");
+    tree.get_root_hash().clone()
+}
+
+/// Check that a lambda state file fits the memory range it's mapped over
+///
+/// The file must be page-aligned and exactly as long as the range. With `pad`, shorter files are
+/// accepted too, and `true` is returned to have their copies padded with `pad`; the file itself
+/// is left untouched.
+pub(crate) fn preflight(path: &str, range_length: u64, pad: bool) -> Result<bool, AdvanceError> {
+    let length = std::fs::metadata(path)
+        .context(LambdaStateIoSnafu { path })?
+        .len();
+    if pad && length < range_length {
+        return Ok(true);
+    }
+    snafu::ensure!(
+        length % PAGE_SIZE == 0,
+        MisalignedLambdaStateSnafu { path, length }
+    );
+    snafu::ensure!(
+        length == range_length,
+        LambdaStateSizeSnafu {
+            path,
+            length,
+            range_length,
+        }
+    );
+    Ok(false)
+}
+
+/// Extend a lambda state file with zeros up to the length of its memory range, in place
+pub(crate) fn pad(path: &str, range_length: u64) -> Result<(), AdvanceError> {
+    OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_len(range_length))
+        .context(LambdaStateIoSnafu { path })
+}
+
+/// Clone a lambda state to a padded private sibling, returning its path
+///
+/// Used to map short lambda states that mustn't be modified, as for inspects.
+pub(crate) fn padded_copy(path: &str, range_length: u64) -> Result<String, AdvanceError> {
+    static COPIES: AtomicU64 = AtomicU64::new(0);
+    let copy_path = format!(
+        "{path}.padded-{}-{}",
+        std::process::id(),
+        COPIES.fetch_add(1, Ordering::Relaxed)
+    );
+    clone_lambda_state(path, &copy_path, false)?;
+    if let Err(err) = pad(&copy_path, range_length) {
+        let _ = std::fs::remove_file(&copy_path);
+        return Err(err);
+    }
+    Ok(copy_path)
+}
+
+/// Check whether the filesystem holding `dir` can reflink files
+///
+/// Probes by reflinking a scratch file inside `dir`.
+pub fn supports_reflink(dir: impl AsRef<Path>) -> Result<bool, AdvanceError> {
+    let probe = dir
+        .as_ref()
+        .join(format!(".reflink-probe-{}", std::process::id()));
+    let probe_copy = probe.with_extension("copy");
+    std::fs::write(&probe, [0; PAGE_SIZE as usize]).context(LambdaStateIoSnafu {
+        path: probe.display().to_string(),
+    })?;
+    let supported = reflink::reflink(&probe, &probe_copy).is_ok();
+    let _ = std::fs::remove_file(&probe_copy);
+    std::fs::remove_file(&probe).context(LambdaStateIoSnafu {
+        path: probe.display().to_string(),
+    })?;
+    Ok(supported)
+}
+
+/// Clone a lambda state, replacing `to`
+///
+/// Reflinks when the filesystem supports it and otherwise falls back to a sparse copy, unless
+/// `require_reflink` is set. Fails without touching `to` when it's the same file as `from`.
+pub(crate) fn clone_lambda_state(
+    from: &str,
+    to: &str,
+    require_reflink: bool,
+) -> Result<(), AdvanceError> {
+    let from_metadata = std::fs::metadata(from).context(LambdaStateIoSnafu { path: from })?;
+    match std::fs::metadata(to) {
+        Ok(to_metadata) => snafu::ensure!(
+            (to_metadata.dev(), to_metadata.ino()) != (from_metadata.dev(), from_metadata.ino()),
+            SameLambdaStateSnafu { path: to }
+        ),
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            return Err(err).context(LambdaStateIoSnafu { path: to });
+        }
+        Err(_) => {}
+    }
+    match std::fs::remove_file(to) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            return Err(err).context(LambdaStateIoSnafu { path: to });
+        }
+        _ => {}
+    }
+    match reflink::reflink(from, to) {
+        Ok(()) => Ok(()),
+        Err(err) if !reflink_unsupported(&err) => Err(err).context(LambdaStateIoSnafu { path: to }),
+        Err(err) if require_reflink => Err(err).context(ReflinkUnavailableSnafu { path: to }),
+        Err(_) => {
+            eprintln!("WARNING: could not reflink lambda state, making a sparse copy instead");
+            sparse_copy(from, to)
+        }
+    }
+}
+
+/// Check whether a reflink failed because the files can't share extents
+///
+/// That's the case when the filesystem doesn't support reflinks or the files are on different
+/// filesystems.
+fn reflink_unsupported(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::ENOSYS | libc::EXDEV | libc::EINVAL)
+    )
+}
+
+/// Copy a file, leaving its holes unallocated in the copy
+///
+/// Only the data regions reported by `SEEK_DATA`/`SEEK_HOLE` are copied.
+pub fn sparse_copy(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), AdvanceError> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    let from_path = from.display().to_string();
+    let to_path = to.display().to_string();
+    let source = File::open(from).context(LambdaStateIoSnafu { path: &from_path })?;
+    let length = source
+        .metadata()
+        .context(LambdaStateIoSnafu { path: &from_path })?
+        .len();
+    let destination = File::create(to)
+        .and_then(|file| file.set_len(length).map(|_| file))
+        .context(LambdaStateIoSnafu { path: &to_path })?;
+
+    let mut buf = vec![0; 256 * PAGE_SIZE as usize];
+    let mut offset = 0;
+    while offset < length {
+        let Some(data_start) = seek(&source, offset, libc::SEEK_DATA)
+            .context(LambdaStateIoSnafu { path: &from_path })?
+        else {
+            break;
+        };
+        let data_end = seek(&source, data_start, libc::SEEK_HOLE)
+            .context(LambdaStateIoSnafu { path: &from_path })?
+            .unwrap_or(length);
+        let mut position = data_start;
+        while position < data_end {
+            let chunk = buf.len().min((data_end - position) as usize);
+            source
+                .read_exact_at(&mut buf[..chunk], position)
+                .context(LambdaStateIoSnafu { path: &from_path })?;
+            destination
+                .write_all_at(&buf[..chunk], position)
+                .context(LambdaStateIoSnafu { path: &to_path })?;
+            position += chunk as u64;
+        }
+        offset = data_end;
+    }
+    destination
+        .sync_all()
+        .context(LambdaStateIoSnafu { path: &to_path })
+}
+
+/// Find the next data or hole offset from `offset`, or `None` when there's no more data
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+    let result = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if result >= 0 {
+        return Ok(Some(result as u64));
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ENXIO) => Ok(None),
+        _ => Err(err),
+    }
+}
+
+/// List the pages of `next` that differ from those of `previous`
+///
+/// Pages are compared by content, as reflinked files share their extents until written. Pages
+/// past the end of `previous` are compared against zeros.
+pub fn dirty_pages(
+    previous: impl AsRef<Path>,
+    next: impl AsRef<Path>,
+) -> Result<Vec<u64>, AdvanceError> {
+    let mut pages = vec![];
+    compare_pages(previous.as_ref(), next.as_ref(), |index, _| {
+        pages.push(index);
+        Ok(())
+    })?;
+    Ok(pages)
+}
+
+/// Write the pages of `next` that differ from those of `previous` to a delta file
+///
+/// The delta holds the length of `next` followed by the index and contents of each dirty page, so
+/// applying it to `previous` reproduces `next`. Returns the dirty pages.
+pub fn write_delta(
+    previous: impl AsRef<Path>,
+    next: impl AsRef<Path>,
+    delta: impl AsRef<Path>,
+) -> Result<Vec<u64>, AdvanceError> {
+    let (next, delta) = (next.as_ref(), delta.as_ref());
+    let delta_path = delta.display().to_string();
+    let length = File::open(next)
+        .and_then(|file| file.metadata())
+        .context(LambdaStateIoSnafu {
+            path: next.display().to_string(),
+        })?
+        .len();
+    let mut writer = File::create(delta)
+        .map(BufWriter::new)
+        .context(LambdaStateIoSnafu { path: &delta_path })?;
+    writer
+        .write_all(DELTA_MAGIC)
+        .and_then(|_| writer.write_all(&length.to_le_bytes()))
+        .context(LambdaStateIoSnafu { path: &delta_path })?;
+    let mut pages = vec![];
+    compare_pages(previous.as_ref(), next, |index, page| {
+        pages.push(index);
+        writer
+            .write_all(&index.to_le_bytes())
+            .and_then(|_| writer.write_all(page))
+            .context(LambdaStateIoSnafu { path: &delta_path })
+    })?;
+    writer
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)
+        .and_then(|file| file.sync_all())
+        .context(LambdaStateIoSnafu { path: &delta_path })?;
+    Ok(pages)
+}
+
+/// Apply a delta written by `write_delta` to a lambda state
+///
+/// The whole delta is validated first, then applied to a reflinked `.partial` sibling of the
+/// state that replaces it once synced, so the state is never left half-updated.
+pub fn apply_delta(delta: impl AsRef<Path>, state: impl AsRef<Path>) -> Result<(), AdvanceError> {
+    let (delta, state) = (delta.as_ref(), state.as_ref());
+    let state_path = state.display().to_string();
+    let partial_path = format!("{state_path}.partial");
+    let length = read_delta(delta, |_, _| Ok(()))?;
+
+    clone_lambda_state(&state_path, &partial_path, false)?;
+    let file = OpenOptions::new()
+        .write(true)
+        .open(&partial_path)
+        .context(LambdaStateIoSnafu {
+            path: &partial_path,
+        })?;
+    let applied = file
+        .set_len(length)
+        .context(LambdaStateIoSnafu {
+            path: &partial_path,
+        })
+        .and_then(|_| {
+            read_delta(delta, |offset, page| {
+                file.write_all_at(page, offset).context(LambdaStateIoSnafu {
+                    path: &partial_path,
+                })
+            })
+        })
+        .and_then(|_| {
+            file.sync_all().context(LambdaStateIoSnafu {
+                path: &partial_path,
+            })
+        });
+    if let Err(err) = applied {
+        let _ = std::fs::remove_file(&partial_path);
+        return Err(err);
+    }
+    std::fs::rename(&partial_path, state)
+        .and_then(|_| sync_parent(&state_path))
+        .context(LambdaStateIoSnafu { path: &state_path })
+}
+
+/// Check a delta file, calling `on_page` with the offset and contents of each of its pages
+///
+/// Returns the length of the lambda state the delta produces.
+fn read_delta(
+    delta: &Path,
+    mut on_page: impl FnMut(u64, &[u8]) -> Result<(), AdvanceError>,
+) -> Result<u64, AdvanceError> {
+    let delta_path = delta.display().to_string();
+    let mut reader = File::open(delta)
+        .map(BufReader::new)
+        .context(LambdaStateIoSnafu { path: &delta_path })?;
+    let mut header = [0; 16];
+    let header_length =
+        read_full(&mut reader, &mut header).context(LambdaStateIoSnafu { path: &delta_path })?;
+    snafu::ensure!(
+        header_length == header.len() && &header[..8] == DELTA_MAGIC,
+        InvalidLambdaStateDeltaSnafu { path: &delta_path }
+    );
+    let length = u64::from_le_bytes(header[8..].try_into().expect("cannot fail"));
+
+    let mut index = [0; 8];
+    let mut page = vec![0; PAGE_SIZE as usize];
+    loop {
+        let index_length =
+            read_full(&mut reader, &mut index).context(LambdaStateIoSnafu { path: &delta_path })?;
+        if index_length == 0 {
+            return Ok(length);
+        }
+        let page_length =
+            read_full(&mut reader, &mut page).context(LambdaStateIoSnafu { path: &delta_path })?;
+        let offset = u64::from_le_bytes(index)
+            .checked_mul(PAGE_SIZE)
+            .filter(|&offset| offset < length)
+            .filter(|_| index_length == index.len() && page_length == page.len())
+            .context(InvalidLambdaStateDeltaSnafu { path: &delta_path })?;
+        on_page(offset, &page)?;
+    }
+}
+
+/// Call `on_dirty` with the index and contents of each page of `next` that differs from `previous`
+fn compare_pages(
+    previous: &Path,
+    next: &Path,
+    mut on_dirty: impl FnMut(u64, &[u8]) -> Result<(), AdvanceError>,
+) -> Result<(), AdvanceError> {
+    let previous_path = previous.display().to_string();
+    let next_path = next.display().to_string();
+    let next_file = File::open(next).context(LambdaStateIoSnafu { path: &next_path })?;
+    let length = next_file
+        .metadata()
+        .context(LambdaStateIoSnafu { path: &next_path })?
+        .len();
+    snafu::ensure!(
+        length % PAGE_SIZE == 0,
+        MisalignedLambdaStateSnafu {
+            path: &next_path,
+            length,
+        }
+    );
+    let mut previous_reader =
+        File::open(previous)
+            .map(BufReader::new)
+            .context(LambdaStateIoSnafu {
+                path: &previous_path,
+            })?;
+    let mut next_reader = BufReader::new(next_file);
+    let mut previous_page = vec![0; PAGE_SIZE as usize];
+    let mut next_page = vec![0; PAGE_SIZE as usize];
+    for index in 0..length / PAGE_SIZE {
+        let previous_length =
+            read_full(&mut previous_reader, &mut previous_page).context(LambdaStateIoSnafu {
+                path: &previous_path,
+            })?;
+        previous_page[previous_length..].fill(0);
+        next_reader
+            .read_exact(&mut next_page)
+            .context(LambdaStateIoSnafu { path: &next_path })?;
+        if previous_page != next_page {
+            on_dirty(index, &next_page)?;
+        }
+    }
+    Ok(())
+}
+
+/// Fill `buf` from `reader` unless it ends first, returning the number of bytes read
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("{name}-{}", std::process::id()))
+            .display()
+            .to_string()
+    }
+
+    #[test]
+    fn test_it_checks_the_size_and_alignment_of_lambda_states() {
+        let path = &temp_path("lambda-state-preflight");
+
+        fs::write(path, vec![0; 2 * PAGE_SIZE as usize]).unwrap();
+        assert!(!preflight(path, 2 * PAGE_SIZE, false).unwrap());
+        let err = preflight(path, 4 * PAGE_SIZE, false).unwrap_err();
+        assert!(
+            matches!(err, AdvanceError::LambdaStateSize { length, .. } if length == 2 * PAGE_SIZE)
+        );
+
+        fs::write(path, vec![1; 100]).unwrap();
+        let err = preflight(path, PAGE_SIZE, false).unwrap_err();
+        assert!(matches!(
+            err,
+            AdvanceError::MisalignedLambdaState { length: 100, .. }
+        ));
+
+        assert!(preflight(path, PAGE_SIZE, true).unwrap());
+        assert_eq!(fs::metadata(path).unwrap().len(), 100);
+        pad(path, PAGE_SIZE).unwrap();
+        assert!(!preflight(path, PAGE_SIZE, true).unwrap());
+        let padded = fs::read(path).unwrap();
+        assert_eq!(padded.len() as u64, PAGE_SIZE);
+        assert!(padded[..100].iter().all(|&byte| byte == 1));
+        assert!(padded[100..].iter().all(|&byte| byte == 0));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_it_computes_the_merkle_root_of_lambda_states() {
+        let path = &temp_path("lambda-state-merkle-root");
+
+        fs::write(path, vec![0; 3 * PAGE_SIZE as usize]).unwrap();
+        let pristine = pristine::Tree::new(LOG2_PAGE_SIZE + 2, LOG2_WORD_SIZE).unwrap();
+        assert_eq!(
+            &merkle_root(path).unwrap(),
+            pristine.get_hash(LOG2_PAGE_SIZE + 2).unwrap()
+        );
+
+        let mut data = vec![0; 2 * PAGE_SIZE as usize];
+        data[..5].copy_from_slice(b"state");
+        data[PAGE_SIZE as usize + 8] = 0xff;
+        fs::write(path, &data).unwrap();
+        let word_hashes = data
+            .chunks(WORD_SIZE)
+            .map(|word| Hasher::digest(word).into())
+            .collect();
+        let tree = Tree::new_from_leaves(
+            LOG2_PAGE_SIZE + 1,
+            LOG2_WORD_SIZE,
+            LOG2_WORD_SIZE,
+            word_hashes,
+        )
+        .unwrap();
+        assert_eq!(&merkle_root(path).unwrap(), tree.get_root_hash());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_it_diffs_lambda_states_and_applies_the_delta() {
+        let previous = &temp_path("lambda-state-previous");
+        let next = &temp_path("lambda-state-next");
+        let delta = &temp_path("lambda-state-delta");
+
+        let previous_data = vec![7; 4 * PAGE_SIZE as usize];
+        let mut next_data = previous_data.clone();
+        next_data[3] = 0;
+        next_data[2 * PAGE_SIZE as usize + 100] = 1;
+        next_data.extend(vec![0; PAGE_SIZE as usize]);
+        fs::write(previous, &previous_data).unwrap();
+        fs::write(next, &next_data).unwrap();
+
+        assert_eq!(dirty_pages(previous, next).unwrap(), vec![0, 2]);
+        assert_eq!(write_delta(previous, next, delta).unwrap(), vec![0, 2]);
+        assert_eq!(fs::metadata(delta).unwrap().len(), 16 + 2 * (8 + PAGE_SIZE));
+        apply_delta(delta, previous).unwrap();
+        assert_eq!(fs::read(previous).unwrap(), next_data);
+        assert!(!Path::new(&format!("{previous}.partial")).exists());
+
+        fs::write(delta, b"not a delta").unwrap();
+        let err = apply_delta(delta, previous).unwrap_err();
+        assert!(matches!(err, AdvanceError::InvalidLambdaStateDelta { .. }));
+
+        fs::write(previous, &previous_data).unwrap();
+        write_delta(previous, next, delta).unwrap();
+        let delta_length = fs::metadata(delta).unwrap().len();
+        File::options()
+            .write(true)
+            .open(delta)
+            .and_then(|file| file.set_len(delta_length - 100))
+            .unwrap();
+        let err = apply_delta(delta, previous).unwrap_err();
+        assert!(matches!(err, AdvanceError::InvalidLambdaStateDelta { .. }));
+        assert_eq!(fs::read(previous).unwrap(), previous_data);
+        for path in [previous, next, delta] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_it_copies_sparse_files() {
+        let from = &temp_path("lambda-state-sparse");
+        let to = &temp_path("lambda-state-sparse-copy");
+        let file = File::create(from).unwrap();
+        file.set_len(1024 * PAGE_SIZE).unwrap();
+        file.write_all_at(b"data", 3 * PAGE_SIZE + 10).unwrap();
+        file.write_all_at(b"tail", 1024 * PAGE_SIZE - 4).unwrap();
+        fs::write(to, b"stale contents").unwrap();
+
+        let err = clone_lambda_state(from, from, false).unwrap_err();
+        assert!(matches!(err, AdvanceError::SameLambdaState { .. }));
+        clone_lambda_state(from, to, false).unwrap();
+        assert_eq!(fs::read(to).unwrap(), fs::read(from).unwrap());
+        fs::remove_file(to).unwrap();
+        sparse_copy(from, to).unwrap();
+        assert_eq!(fs::read(to).unwrap(), fs::read(from).unwrap());
+        for path in [from, to] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,471 @@
+use crate::error::{
+    AdvanceError, InvalidLambdaStoreChainSnafu, LambdaStateIoSnafu, StaleLambdaStoreIndexSnafu,
+};
+use crate::hash::Hash;
+use crate::lambda_state::{clone_lambda_state, merkle_root};
+use crate::lock::StateLock;
+use crate::transition::{recover_journal, sync_parent};
+use crate::RunAdvanceLambdaStatePaths;
+use alloy_primitives::U256;
+use snafu::{OptionExt, ResultExt};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Content-addressed store of lambda state versions
+///
+/// Each version is named after its merkle root and length, since roots are computed over files
+/// padded to a power of two, and stored once, however many inputs left the state in it. A chain
+/// maps the index of every accepted input to the version it produced, so the state after any
+/// input resolves to a path. New versions are reflinked from the previous one by the runner and
+/// moved into the store, so unchanged pages stay shared between versions.
+///
+/// The store directory is locked for as long as the store is open, so a second opener fails with
+/// `StateBusy`.
+pub struct LambdaStore {
+    dir: PathBuf,
+    _lock: StateLock,
+    /// Version the chain starts from, until it's collected
+    initial: Option<Version>,
+    /// Version produced by each accepted input
+    chain: BTreeMap<U256, Version>,
+}
+
+/// Lambda state version, identified by its merkle root and length
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Version {
+    root: Hash,
+    length: u64,
+}
+
+impl Version {
+    fn name(&self) -> String {
+        format!("{}-{}", hex::encode(self.root.data()), self.length)
+    }
+}
+
+impl LambdaStore {
+    /// Open the store in `dir`, creating it if needed
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, AdvanceError> {
+        let dir = dir.into();
+        for subdir in [dir.join("versions"), dir.join("work")] {
+            fs::create_dir_all(&subdir).context(LambdaStateIoSnafu {
+                path: subdir.display().to_string(),
+            })?;
+        }
+        let lock = StateLock::directory(&dir.display().to_string(), true)?;
+        let mut store = Self {
+            dir,
+            _lock: lock,
+            initial: None,
+            chain: BTreeMap::new(),
+        };
+        let chain_path = store.chain_path();
+        let path = chain_path.display().to_string();
+        let contents = match fs::read_to_string(&chain_path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err).context(LambdaStateIoSnafu { path }),
+        };
+        for line in contents.lines() {
+            let (key, version) = parse_chain_line(line)
+                .context(InvalidLambdaStoreChainSnafu { path: &path, line })?;
+            match key {
+                Some(index) => {
+                    store.chain.insert(index, version);
+                }
+                None => store.initial = Some(version),
+            }
+        }
+        store.finish_commits()?;
+        Ok(store)
+    }
+
+    /// Import the state the chain starts from
+    pub fn init(&mut self, path: impl AsRef<Path>) -> Result<Hash, AdvanceError> {
+        let version = self.import(path.as_ref(), false)?;
+        self.append_chain_line(&format!("initial {}", chain_entry(&version)))?;
+        let root = version.root.clone();
+        self.initial = Some(version);
+        Ok(root)
+    }
+
+    /// Journal for the transitions of runners using the store
+    ///
+    /// Configure runners with it, as the next states change from input to input.
+    pub fn journal_path(&self) -> String {
+        self.dir.join("transition.journal").display().to_string()
+    }
+
+    /// Recover a transition interrupted by a crash, and list the inputs whose next state is
+    /// waiting to be committed
+    ///
+    /// Those are accepted inputs, unless the runner's own records say otherwise.
+    pub fn recover(&self) -> Result<Vec<U256>, AdvanceError> {
+        recover_journal(&self.journal_path())?;
+        let mut indexes: Vec<U256> = self
+            .entry_names("work")?
+            .iter()
+            .filter_map(|name| name.parse().ok())
+            .collect();
+        indexes.sort();
+        Ok(indexes)
+    }
+
+    /// Lambda state paths for running the input with the given index
+    ///
+    /// The previous state is the latest version in the chain and the next one a scratch file to
+    /// be committed once the input is accepted.
+    pub fn lambda_state_paths(&self, index: U256) -> Option<RunAdvanceLambdaStatePaths> {
+        let previous = self.latest_version()?;
+        Some(RunAdvanceLambdaStatePaths {
+            lambda_state_previous_path: self.version_path(previous).display().to_string(),
+            lambda_state_next_path: self.work_path(index).display().to_string(),
+        })
+    }
+
+    /// Record the next state of an accepted input as its version
+    ///
+    /// Inputs must be committed in increasing index order. Fails with `StateBusy` while a runner
+    /// still uses the next state.
+    pub fn commit(&mut self, index: U256) -> Result<Hash, AdvanceError> {
+        if let Some(&latest) = self.chain.keys().next_back() {
+            snafu::ensure!(index > latest, StaleLambdaStoreIndexSnafu { index, latest });
+        }
+        let work_path = self.work_path(index);
+        let lock = StateLock::lambda_state(&work_path.display().to_string(), true)?;
+        let version = self.version_of(&work_path)?;
+        // The chain is updated first, so an interrupted commit is finished by the next `open`
+        self.append_chain_line(&format!("{index} {}", chain_entry(&version)))?;
+        self.store_version(&work_path, &version, true)?;
+        let root = version.root.clone();
+        self.chain.insert(index, version);
+        lock.remove()?;
+        Ok(root)
+    }
+
+    /// Path of the state after the input with the given index
+    ///
+    /// Inputs that weren't accepted leave the state of the previous one. Returns `None` for
+    /// inputs before the retained part of the chain.
+    pub fn resolve(&self, index: U256) -> Option<PathBuf> {
+        let version = match self.chain.range(..=index).next_back() {
+            Some((_, version)) => version,
+            None => self.initial.as_ref()?,
+        };
+        Some(self.version_path(version))
+    }
+
+    /// Merkle root of the latest version in the chain
+    pub fn latest(&self) -> Option<&Hash> {
+        self.latest_version().map(|version| &version.root)
+    }
+
+    /// Drop all but the latest `keep` inputs from the chain and delete the versions no longer
+    /// referenced
+    ///
+    /// At least the latest version is always kept, and versions still used by a runner are left
+    /// for a later collection. Lock files left by inputs that weren't committed are removed too.
+    pub fn collect_garbage(&mut self, keep: usize) -> Result<(), AdvanceError> {
+        let keep = keep.max(1);
+        if self.chain.len() > keep {
+            let first_kept = *self
+                .chain
+                .keys()
+                .nth(self.chain.len() - keep)
+                .expect("cannot fail");
+            self.chain = self.chain.split_off(&first_kept);
+            self.initial = None;
+        }
+        self.rewrite_chain()?;
+
+        let referenced: HashSet<String> = self
+            .chain
+            .values()
+            .chain(self.initial.as_ref())
+            .map(Version::name)
+            .collect();
+        let mut unreferenced = BTreeSet::new();
+        for name in self.entry_names("versions")? {
+            let version = name.strip_suffix(".lock").unwrap_or(&name);
+            if !referenced.contains(version) {
+                unreferenced.insert(version.to_string());
+            }
+        }
+        for version in unreferenced {
+            remove_unless_busy(&self.dir.join("versions").join(version))?;
+        }
+
+        for name in self.entry_names("work")? {
+            if let Some(work_name) = name.strip_suffix(".lock") {
+                let work_path = self.dir.join("work").join(work_name);
+                if !work_path.exists() {
+                    remove_unless_busy(&work_path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Names of the entries of a subdirectory of the store
+    fn entry_names(&self, subdir: &str) -> Result<Vec<String>, AdvanceError> {
+        let dir = self.dir.join(subdir);
+        let path = dir.display().to_string();
+        let mut names = vec![];
+        for entry in fs::read_dir(&dir).context(LambdaStateIoSnafu { path: &path })? {
+            let entry = entry.context(LambdaStateIoSnafu { path: &path })?;
+            if let Ok(name) = entry.file_name().into_string() {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+
+    fn latest_version(&self) -> Option<&Version> {
+        self.chain.values().next_back().or(self.initial.as_ref())
+    }
+
+    /// Import a state into the store
+    fn import(&self, path: &Path, move_file: bool) -> Result<Version, AdvanceError> {
+        let version = self.version_of(path)?;
+        self.store_version(path, &version, move_file)?;
+        Ok(version)
+    }
+
+    fn version_of(&self, path: &Path) -> Result<Version, AdvanceError> {
+        let length = fs::metadata(path)
+            .context(LambdaStateIoSnafu {
+                path: path.display().to_string(),
+            })?
+            .len();
+        Ok(Version {
+            root: merkle_root(path)?,
+            length,
+        })
+    }
+
+    /// Move (or copy) a state to its version, unless the store already holds it
+    fn store_version(
+        &self,
+        path: &Path,
+        version: &Version,
+        move_file: bool,
+    ) -> Result<(), AdvanceError> {
+        let source = path.display().to_string();
+        let version_path = self.version_path(version).display().to_string();
+        if Path::new(&version_path).exists() {
+            if move_file {
+                fs::remove_file(path).context(LambdaStateIoSnafu { path: source })?;
+            }
+            return Ok(());
+        }
+        if move_file {
+            fs::rename(path, &version_path).context(LambdaStateIoSnafu { path: source })?;
+        } else {
+            clone_lambda_state(&source, &version_path, false)?;
+        }
+        sync_parent(&version_path).context(LambdaStateIoSnafu {
+            path: &version_path,
+        })
+    }
+
+    /// Move the next states of commits interrupted after updating the chain into the store
+    fn finish_commits(&self) -> Result<(), AdvanceError> {
+        for (&index, version) in &self.chain {
+            let work_path = self.work_path(index);
+            if !self.version_path(version).exists() && work_path.exists() {
+                self.store_version(&work_path, version, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Append a line to the chain file and make it durable
+    fn append_chain_line(&self, line: &str) -> Result<(), AdvanceError> {
+        let chain_path = self.chain_path().display().to_string();
+        let created = !Path::new(&chain_path).exists();
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&chain_path)
+            .and_then(|mut file| {
+                writeln!(file, "{line}")?;
+                file.sync_all()
+            })
+            .and_then(|_| {
+                if created {
+                    sync_parent(&chain_path)
+                } else {
+                    Ok(())
+                }
+            })
+            .context(LambdaStateIoSnafu { path: &chain_path })
+    }
+
+    /// Replace the chain file with the chain in memory
+    fn rewrite_chain(&self) -> Result<(), AdvanceError> {
+        let mut contents = String::new();
+        if let Some(initial) = &self.initial {
+            contents += &format!("initial {}\n", chain_entry(initial));
+        }
+        for (index, version) in &self.chain {
+            contents += &format!("{index} {}\n", chain_entry(version));
+        }
+        let chain_path = self.chain_path().display().to_string();
+        let temp_path = format!("{chain_path}.tmp");
+        File::create(&temp_path)
+            .and_then(|mut file| {
+                file.write_all(contents.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&temp_path, &chain_path))
+            .and_then(|_| sync_parent(&chain_path))
+            .context(LambdaStateIoSnafu { path: &chain_path })
+    }
+
+    fn chain_path(&self) -> PathBuf {
+        self.dir.join("chain")
+    }
+
+    fn version_path(&self, version: &Version) -> PathBuf {
+        self.dir.join("versions").join(version.name())
+    }
+
+    fn work_path(&self, index: U256) -> PathBuf {
+        self.dir.join("work").join(index.to_string())
+    }
+}
+
+/// Remove a lambda state file and its lock file, unless a runner holds the lock
+fn remove_unless_busy(path: &Path) -> Result<(), AdvanceError> {
+    let path = path.display().to_string();
+    let lock = match StateLock::lambda_state(&path, true) {
+        Err(AdvanceError::StateBusy { .. }) => return Ok(()),
+        lock => lock?,
+    };
+    match fs::remove_file(&path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            return Err(err).context(LambdaStateIoSnafu { path });
+        }
+        _ => {}
+    }
+    lock.remove()
+}
+
+/// Merkle root and length of a version, as written in the chain file
+fn chain_entry(version: &Version) -> String {
+    format!("{} {}", hex::encode(version.root.data()), version.length)
+}
+
+/// Parse an `initial <root> <length>` or `<index> <root> <length>` line of the chain file
+fn parse_chain_line(line: &str) -> Option<(Option<U256>, Version)> {
+    let mut fields = line.split(' ');
+    let (key, root, length) = (fields.next()?, fields.next()?, fields.next()?);
+    if fields.next().is_some() {
+        return None;
+    }
+    let version = Version {
+        root: Hash::try_from(hex::decode(root).ok()?).ok()?,
+        length: length.parse().ok()?,
+    };
+    let index = match key {
+        "initial" => None,
+        index => Some(index.parse().ok()?),
+    };
+    Some((index, version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lambda_state::PAGE_SIZE;
+
+    fn write_next(store: &LambdaStore, index: u64, data: &[u8]) {
+        let paths = store.lambda_state_paths(U256::from(index)).unwrap();
+        fs::copy(
+            &paths.lambda_state_previous_path,
+            &paths.lambda_state_next_path,
+        )
+        .unwrap();
+        fs::write(&paths.lambda_state_next_path, data).unwrap();
+    }
+
+    #[test]
+    fn test_it_stores_and_resolves_lambda_state_versions() {
+        let dir = std::env::temp_dir().join(format!("lambda-store-{}", std::process::id()));
+        let initial_path = dir.with_extension("initial");
+        let initial = vec![0; PAGE_SIZE as usize];
+        fs::write(&initial_path, &initial).unwrap();
+
+        let mut store = LambdaStore::open(&dir).unwrap();
+        let initial_hash = store.init(&initial_path).unwrap();
+        let changed = vec![1; PAGE_SIZE as usize];
+        write_next(&store, 2, &changed);
+        let changed_hash = store.commit(U256::from(2)).unwrap();
+        write_next(&store, 5, &changed);
+        assert_eq!(store.commit(U256::from(5)).unwrap(), changed_hash);
+        assert_eq!(fs::read_dir(dir.join("versions")).unwrap().count(), 2);
+        let err = store.commit(U256::from(4)).unwrap_err();
+        assert!(matches!(err, AdvanceError::StaleLambdaStoreIndex { .. }));
+        let err = LambdaStore::open(&dir).err().unwrap();
+        assert!(matches!(err, AdvanceError::StateBusy { .. }));
+        drop(store);
+
+        let store = LambdaStore::open(&dir).unwrap();
+        assert_eq!(store.latest(), Some(&changed_hash));
+        assert_eq!(
+            fs::read(store.resolve(U256::from(1)).unwrap()).unwrap(),
+            initial
+        );
+        assert_eq!(
+            fs::read(store.resolve(U256::from(3)).unwrap()).unwrap(),
+            changed
+        );
+
+        assert_eq!(fs::read_dir(dir.join("work")).unwrap().count(), 0);
+
+        let mut store = store;
+        let initial_version = store.version_path(&Version {
+            root: initial_hash,
+            length: PAGE_SIZE,
+        });
+        let lock = StateLock::lambda_state(&initial_version.display().to_string(), false).unwrap();
+        store.collect_garbage(1).unwrap();
+        assert_eq!(store.resolve(U256::from(3)), None);
+        assert!(store.resolve(U256::from(5)).is_some());
+        assert!(initial_version.exists());
+        drop(lock);
+        store.collect_garbage(1).unwrap();
+        assert_eq!(fs::read_dir(dir.join("versions")).unwrap().count(), 1);
+        drop(store);
+        assert_eq!(LambdaStore::open(&dir).unwrap().chain.len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(&initial_path).unwrap();
+    }
+
+    #[test]
+    fn test_it_keeps_versions_of_different_lengths_apart() {
+        let dir = std::env::temp_dir().join(format!("lambda-store-lengths-{}", std::process::id()));
+        let initial_path = dir.with_extension("initial");
+        fs::write(&initial_path, vec![0; 3 * PAGE_SIZE as usize]).unwrap();
+
+        let mut store = LambdaStore::open(&dir).unwrap();
+        let initial_hash = store.init(&initial_path).unwrap();
+        write_next(&store, 1, &vec![0; 4 * PAGE_SIZE as usize]);
+        assert_eq!(store.recover().unwrap(), vec![U256::from(1)]);
+        assert_eq!(store.commit(U256::from(1)).unwrap(), initial_hash);
+        assert!(store.recover().unwrap().is_empty());
+        assert_eq!(fs::read_dir(dir.join("versions")).unwrap().count(), 2);
+        let length = |index| {
+            let path = store.resolve(U256::from(index)).unwrap();
+            fs::metadata(path).unwrap().len()
+        };
+        assert_eq!(length(0), 3 * PAGE_SIZE);
+        assert_eq!(length(1), 4 * PAGE_SIZE);
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(&initial_path).unwrap();
+    }
+}
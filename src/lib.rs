@@ -1,212 +1,144 @@
-use alloy_primitives::{address, U256};
-use alloy_sol_types::{sol, SolCall};
-use cartesi_machine::{
-    cartesi_machine_sys::{
-        CM_CMIO_YIELD_REASON_ADVANCE_STATE, CM_CMIO_YIELD_REASON_INSPECT_STATE, CM_REG_IFLAGS_Y,
-    },
-    config::runtime::{ConcurrencyRuntimeConfig, HTIFRuntimeConfig, RuntimeConfig},
-    constants::cmio::{commands, tohost::manual::RX_ACCEPTED},
-    machine::Machine,
-    types::cmio::{AutomaticReason, CmioRequest, CmioResponseReason, ManualReason},
-};
-use std::error::Error;
-use std::fs::File;
-use std::future::Future;
-use std::path::Path;
-use std::pin::Pin;
-use std::{collections::HashMap, io::ErrorKind};
+mod callbacks;
+mod cmio;
+mod console;
+mod error;
 pub mod hash;
+mod input;
+pub mod lambda_state;
+mod lambda_store;
+mod limits;
+mod lock;
 mod merkle_tree;
+pub mod outputs;
 pub mod proofs;
+mod runner;
+mod session;
+mod transition;
+
+pub use callbacks::{Callback, DataCallback, GioFuture, GioResponse, GioResult, ProgressCallback};
+pub use console::{Console, ConsoleCallback};
+pub use error::AdvanceError;
+pub use input::{AdvanceInput, AdvanceMetadata};
+pub use lambda_store::LambdaStore;
+pub use limits::CancellationHandle;
+pub use outputs::Output;
+pub use runner::{AdvanceRunner, AdvanceRunnerBuilder, RunnerConfig, SnapshotStorage};
+pub use session::Session;
+pub use transition::{pending_journals, recover_journal};
+
+use std::time::Duration;
 
 const MEMORY_RANGE_CONFIG_START: u64 = 0x90000000000000;
-#[derive(PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum YieldManualReason {
     Accepted,
     Rejected,
     Exception,
+    /// The machine used up its cycle limit without accepting or rejecting the request
+    CycleLimitExceeded,
+    /// The deadline passed before the machine accepted or rejected the request
+    DeadlineExceeded,
+    /// The request was cancelled through its cancellation handle
+    Cancelled,
 }
-pub struct RunAdvanceLambdaStatePaths {
-    pub lambda_state_previous_path: String,
-    pub lambda_state_next_path: String,
-}
-pub async fn run_advance(
-    machine_snapshot: String,
-    lambda_state_paths: Option<RunAdvanceLambdaStatePaths>,
-    payload: Vec<u8>,
-    metadata: HashMap<Vec<u8>, Vec<u8>>,
-    report_callback: &mut impl FnMut(u16, &[u8]) -> Result<(u16, Vec<u8>), Box<dyn Error>>,
-    output_callback: &mut impl FnMut(u16, &[u8]) -> Result<(u16, Vec<u8>), Box<dyn Error>>,
-    finish_callback: &mut impl FnMut(u16, &[u8]) -> Result<(u16, Vec<u8>), Box<dyn Error>>,
-    callbacks: HashMap<u32, Callback>,
-    no_console_putchar: bool,
-) -> Result<YieldManualReason, Box<dyn Error>> {
-    if let Some(lambda_state_paths) = &lambda_state_paths {
-        match reflink::reflink_or_copy(
-            &lambda_state_paths.lambda_state_previous_path,
-            &lambda_state_paths.lambda_state_next_path,
-        ) {
-            Ok(Some(_)) => {
-                eprintln!("WARNING: could not reflink lambda state, copying instead");
-            }
-            Ok(None) => {}
-            Err(e) => return Err(Box::new(e)),
-        }
-    }
-
-    let mut machine = Machine::load(
-        std::path::Path::new(machine_snapshot.as_str()),
-        &RuntimeConfig {
-            skip_root_hash_check: Some(true),
-            skip_root_hash_store: Some(true),
-            concurrency: Some(ConcurrencyRuntimeConfig {
-                update_merkle_tree: Some(0),
-            }),
-            htif: Some(HTIFRuntimeConfig {
-                no_console_putchar: Some(no_console_putchar),
-            }),
-            skip_version_check: Some(false),
-            soft_yield: Some(false),
-        },
-    )
-    .unwrap();
-    if let Some(lambda_state_paths) = lambda_state_paths {
-        let lambda_state_previous_file =
-            File::open(lambda_state_paths.lambda_state_previous_path).unwrap();
-        let lambda_state_previous_file_size = lambda_state_previous_file.metadata().unwrap().len();
-        let filename = Path::new(&lambda_state_paths.lambda_state_next_path);
-        machine
-            .replace_memory_range(
-                MEMORY_RANGE_CONFIG_START,
-                lambda_state_previous_file_size,
-                true,
-                Some(filename),
-            )
-            .unwrap();
-    }
 
-    let cmdio = machine.receive_cmio_request().unwrap();
-
-    if cmdio.reason() == RX_ACCEPTED && cmdio.cmd() == commands::YIELD_MANUAL {
-        let payload = payload;
-        let encoded = encode_evm_advance(payload);
-        machine
-            .send_cmio_response(CmioResponseReason::Advance, &encoded)
-            .unwrap();
-        //TODO send gio response with metadata etc.
-
-        machine.write_reg(CM_REG_IFLAGS_Y, 0)?;
-    } else {
-        return Err(Box::new(std::io::Error::new(
-            ErrorKind::Other,
-            format!("current reason is {:?}, but 0 was expected", cmdio.reason()),
-        )));
+impl YieldManualReason {
+    /// Whether the run was interrupted by the runner's limits before the guest finished
+    pub fn is_interrupted(&self) -> bool {
+        matches!(
+            self,
+            Self::CycleLimitExceeded | Self::DeadlineExceeded | Self::Cancelled
+        )
     }
+}
+/// Result of an advance-state request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdvanceOutcome {
+    pub reason: YieldManualReason,
+    /// Message of the exception raised by the guest, if it raised one
+    pub exception_message: Option<String>,
+    /// Outputs emitted by the guest, in order
+    pub outputs: Vec<Vec<u8>>,
+    /// Reports emitted by the guest, in order
+    pub reports: Vec<Vec<u8>>,
+    /// Outputs root hash reported by the guest, if it accepted the input
+    pub outputs_root_hash: Option<hash::Hash>,
+    /// Counters and timings of the run
+    pub stats: RunStats,
+    /// Whether the next lambda state was discarded because the input wasn't accepted
+    pub lambda_state_rolled_back: bool,
+    /// Snapshot of the machine after the input, if snapshots are stored and it was accepted
+    pub stored_snapshot: Option<StoredSnapshot>,
+}
+/// Result of an inspect-state query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InspectOutcome {
+    pub reason: YieldManualReason,
+    /// Message of the exception raised by the guest, if it raised one
+    pub exception_message: Option<String>,
+    /// Reports emitted by the guest, in order
+    pub reports: Vec<Vec<u8>>,
+    /// Counters and timings of the run
+    pub stats: RunStats,
+}
+/// Counters and timings of a request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunStats {
+    /// Value of `mcycle` when the run started
+    pub mcycle_start: u64,
+    /// Value of `mcycle` when the run ended
+    pub mcycle_end: u64,
+    /// Number of GIO requests answered
+    pub gio_round_trips: u64,
+    pub outputs: u64,
+    pub reports: u64,
+    /// Time spent loading the snapshot, if it was loaded for this request
+    pub load_time: Option<Duration>,
+    /// Time spent replacing memory ranges with the lambda state, if it was mapped for this request
+    pub replace_time: Option<Duration>,
+    /// Wall-clock time spent running the machine and serving its yields
+    pub run_time: Duration,
+}
 
-    let max_cycles = u64::MAX;
-    loop {
-        if !machine.iflags_y().unwrap() {
-            let _ = Some(machine.run(max_cycles).unwrap());
-        }
-        let cmdio = machine.receive_cmio_request().unwrap();
-        let reason = cmdio.reason();
-        match cmdio {
-            CmioRequest::Automatic(automatic_reason) => (match automatic_reason {
-                AutomaticReason::TxReport { data } => {
-                    report_callback(reason, &data)?;
-                }
-                AutomaticReason::TxOutput { data } => {
-                    output_callback(reason, &data)?;
-                }
-                _ => {
-                    return Err(Box::new(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("unknown reason {:?}", reason),
-                    )));
-                }
-            },),
-            CmioRequest::Manual(manual_reason) => (match manual_reason {
-                ManualReason::RxAccepted {
-                    output_hashes_root_hash,
-                } => {
-                    finish_callback(reason, &output_hashes_root_hash)?;
-                    return Ok(YieldManualReason::Accepted);
-                }
-                ManualReason::RxRejected => {
-                    finish_callback(reason, &[])?;
-                    return Ok(YieldManualReason::Rejected);
-                }
-                ManualReason::TxException { message } => {
-                    finish_callback(reason, message.as_bytes())?;
-                    return Ok(YieldManualReason::Exception);
-                }
-                ManualReason::GIO { domain: _, data } => {
-                    match callbacks.get(&(reason as u32)) {
-                        Some(unknown_gio_callback) => {
-                            let callback_output = match unknown_gio_callback {
-                                Callback::Sync(sync_callback) => sync_callback(reason, data)?,
-                                Callback::Async(async_callback) => {
-                                    async_callback(reason, data).await?
-                                }
-                            };
-                            let cmdio_send_reason = match reason as u32 {
-                                CM_CMIO_YIELD_REASON_ADVANCE_STATE => CmioResponseReason::Advance,
-                                CM_CMIO_YIELD_REASON_INSPECT_STATE => CmioResponseReason::Inspect,
-                                _ => {
-                                    return Err(Box::new(std::io::Error::new(
-                                        std::io::ErrorKind::Other,
-                                        format!("Unknown cmdio reason"),
-                                    )));
-                                }
-                            };
-                            machine
-                                .send_cmio_response(cmdio_send_reason, &callback_output)
-                                .unwrap();
-                        }
-                        None => {
-                            println!("No callback found");
-                            drop(machine);
-                            return Err(Box::new(std::io::Error::new(
-                                std::io::ErrorKind::Other,
-                                format!("No callback found"),
-                            )));
-                        }
-                    };
-                }
-            },),
-        };
-        machine.write_reg(CM_REG_IFLAGS_Y, 0)?;
+impl RunStats {
+    /// Machine cycles spent on the run
+    pub fn cycles(&self) -> u64 {
+        self.mcycle_end - self.mcycle_start
     }
 }
-pub enum Callback {
-    Sync(Box<dyn Fn(u16, Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>>>),
-    Async(
-        Box<dyn Fn(u16, Vec<u8>) -> Pin<Box<dyn Future<Output = Result<Vec<u8>, Box<dyn Error>>>>>>,
-    ),
+/// Machine snapshot stored after an accepted advance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredSnapshot {
+    /// Directory the snapshot was stored in
+    pub path: String,
+    /// Root hash of the stored machine, if requested
+    pub root_hash: Option<hash::Hash>,
+}
+pub struct RunAdvanceLambdaStatePaths {
+    pub lambda_state_previous_path: String,
+    pub lambda_state_next_path: String,
+}
+/// Lambda state file mapped over a memory range of the machine
+///
+/// The previous file is reflinked to the next one, which replaces the memory range starting at
+/// `start`. The files must be as long as that range is in the snapshot's configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LambdaStateRange {
+    /// Start address of the memory range
+    pub start: u64,
+    pub lambda_state_previous_path: String,
+    pub lambda_state_next_path: String,
+    /// Whether the guest's writes reach the next file, rather than being discarded
+    pub shared: bool,
 }
 
-fn encode_evm_advance(payload: Vec<u8>) -> Vec<u8> {
-    sol! { interface Inputs {
-        function EvmAdvance(
-            uint256 chainId,
-            address appContract,
-            address msgSender,
-            uint256 blockNumber,
-            uint256 blockTimestamp,
-            uint256 prevRandao,
-            uint256 index,
-            bytes calldata payload
-        ) external;
-    } };
-    let call = Inputs::EvmAdvanceCall {
-        chainId: U256::from(0),
-        appContract: address!(),
-        msgSender: address!(),
-        blockNumber: U256::from(0),
-        blockTimestamp: U256::from(0),
-        prevRandao: U256::from(0),
-        index: U256::from(0),
-        payload: payload.into(),
-    };
-    call.abi_encode()
+impl From<RunAdvanceLambdaStatePaths> for LambdaStateRange {
+    fn from(paths: RunAdvanceLambdaStatePaths) -> Self {
+        Self {
+            start: MEMORY_RANGE_CONFIG_START,
+            lambda_state_previous_path: paths.lambda_state_previous_path,
+            lambda_state_next_path: paths.lambda_state_next_path,
+            shared: true,
+        }
+    }
 }
@@ -0,0 +1,293 @@
+use crate::YieldManualReason;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
+
+/// Number of cycles the machine runs between checks of the deadline and cancellation handle
+pub(crate) const RUN_SLICE_CYCLES: u64 = 1 << 24;
+
+/// Bounds on the work spent on a single request
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RunLimits {
+    /// Maximum number of machine cycles spent on the request
+    pub cycle_limit: Option<u64>,
+    /// Instant after which the request is abandoned
+    pub deadline: Option<Instant>,
+    /// Handle used to abandon the request from another task or thread
+    pub cancellation: Option<CancellationHandle>,
+}
+
+impl RunLimits {
+    /// Reason the request must stop, if it was cancelled or its deadline passed
+    pub fn interruption(&self) -> Option<YieldManualReason> {
+        if self
+            .cancellation
+            .as_ref()
+            .is_some_and(CancellationHandle::is_cancelled)
+        {
+            return Some(YieldManualReason::Cancelled);
+        }
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Some(YieldManualReason::DeadlineExceeded);
+        }
+        None
+    }
+
+    /// Wait for `future`, giving up as soon as the request is cancelled or its deadline passes
+    pub fn interruptible<F: Future + Unpin>(&self, future: F) -> Interruptible<'_, F> {
+        Interruptible {
+            future,
+            limits: self,
+            registration: None,
+            timer: None,
+        }
+    }
+}
+
+/// Cloneable handle for cooperatively cancelling a running request
+///
+/// A cancelled handle stays cancelled: later requests of runners observing it are cancelled
+/// right away, until the handle is reset.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationHandle {
+    inner: Arc<CancellationState>,
+}
+
+#[derive(Debug, Default)]
+struct CancellationState {
+    cancelled: AtomicBool,
+    /// Wakers of the tasks waiting on something other than the machine, by registration
+    wakers: Mutex<HashMap<u64, Waker>>,
+    next_registration: AtomicU64,
+}
+
+impl CancellationHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request the cancellation of every run observing this handle
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        let wakers = std::mem::take(&mut *self.lock_wakers());
+        for waker in wakers.into_values() {
+            waker.wake();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Let runs observing this handle proceed again
+    pub fn reset(&self) {
+        self.inner.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    /// Have `cancel` wake `waker`, replacing the waker of an earlier registration
+    fn register(&self, registration: Option<u64>, waker: &Waker) -> u64 {
+        let registration = registration
+            .unwrap_or_else(|| self.inner.next_registration.fetch_add(1, Ordering::Relaxed));
+        self.lock_wakers().insert(registration, waker.clone());
+        registration
+    }
+
+    fn deregister(&self, registration: u64) {
+        self.lock_wakers().remove(&registration);
+    }
+
+    fn lock_wakers(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Waker>> {
+        self.inner
+            .wakers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Future returned by `RunLimits::interruptible`
+pub(crate) struct Interruptible<'a, F> {
+    future: F,
+    limits: &'a RunLimits,
+    /// Registration of the task's waker with the cancellation handle
+    registration: Option<u64>,
+    /// Thread waking the task at the deadline
+    timer: Option<DeadlineTimer>,
+}
+
+impl<F: Future + Unpin> Future for Interruptible<'_, F> {
+    type Output = Result<F::Output, YieldManualReason>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        if let Some(interruption) = this.limits.interruption() {
+            return Poll::Ready(Err(interruption));
+        }
+        if let Poll::Ready(output) = Pin::new(&mut this.future).poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        if let Some(cancellation) = &this.limits.cancellation {
+            this.registration = Some(cancellation.register(this.registration, cx.waker()));
+        }
+        if let Some(deadline) = this.limits.deadline {
+            this.timer
+                .get_or_insert_with(|| DeadlineTimer::start(deadline))
+                .set_waker(cx.waker());
+        }
+        // The request may have been interrupted before the waker was registered
+        match this.limits.interruption() {
+            Some(interruption) => Poll::Ready(Err(interruption)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<F> Drop for Interruptible<'_, F> {
+    fn drop(&mut self) {
+        if let (Some(cancellation), Some(registration)) =
+            (&self.limits.cancellation, self.registration)
+        {
+            cancellation.deregister(registration);
+        }
+    }
+}
+
+/// Background thread waking a task once a deadline passes, stopped when dropped
+struct DeadlineTimer {
+    shared: Arc<(Mutex<TimerState>, Condvar)>,
+}
+
+#[derive(Default)]
+struct TimerState {
+    waker: Option<Waker>,
+    stopped: bool,
+}
+
+impl DeadlineTimer {
+    fn start(deadline: Instant) -> Self {
+        let shared = Arc::new((Mutex::new(TimerState::default()), Condvar::new()));
+        let thread_shared = shared.clone();
+        std::thread::spawn(move || {
+            let (state, condvar) = &*thread_shared;
+            let mut state = state
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            while !state.stopped {
+                let now = Instant::now();
+                if now >= deadline {
+                    if let Some(waker) = state.waker.take() {
+                        waker.wake();
+                    }
+                    return;
+                }
+                state = condvar
+                    .wait_timeout(state, deadline - now)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .0;
+            }
+        });
+        Self { shared }
+    }
+
+    fn set_waker(&self, waker: &Waker) {
+        self.lock_state().waker = Some(waker.clone());
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, TimerState> {
+        self.shared
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Drop for DeadlineTimer {
+    fn drop(&mut self) {
+        self.lock_state().stopped = true;
+        self.shared.1.notify_one();
+    }
+}
+
+/// Give control back to the executor once, so that other tasks get to run between slices
+pub(crate) fn yield_now() -> impl Future<Output = ()> {
+    YieldNow { yielded: false }
+}
+
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::task::Wake;
+    use std::time::Duration;
+
+    struct WakeFlag(AtomicBool);
+
+    impl Wake for WakeFlag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_it_yields_to_the_executor_once() {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut future = std::pin::pin!(yield_now());
+        assert_eq!(future.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(future.as_mut().poll(&mut cx), Poll::Ready(()));
+    }
+
+    #[test]
+    fn test_it_interrupts_waits_on_cancellation_and_deadline() {
+        let flag = Arc::new(WakeFlag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let cancellation = CancellationHandle::new();
+        let limits = RunLimits {
+            cancellation: Some(cancellation.clone()),
+            ..Default::default()
+        };
+        let mut wait = limits.interruptible(std::future::pending::<()>());
+        assert!(Pin::new(&mut wait).poll(&mut cx).is_pending());
+        cancellation.cancel();
+        assert!(flag.0.swap(false, Ordering::SeqCst));
+        let interrupted = Pin::new(&mut wait).poll(&mut cx);
+        assert_eq!(interrupted, Poll::Ready(Err(YieldManualReason::Cancelled)));
+
+        let limits = RunLimits {
+            deadline: Some(Instant::now() + Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let mut wait = limits.interruptible(std::future::pending::<()>());
+        assert!(Pin::new(&mut wait).poll(&mut cx).is_pending());
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(flag.0.load(Ordering::SeqCst));
+        let interrupted = Pin::new(&mut wait).poll(&mut cx);
+        assert_eq!(
+            interrupted,
+            Poll::Ready(Err(YieldManualReason::DeadlineExceeded))
+        );
+    }
+}
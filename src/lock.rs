@@ -0,0 +1,115 @@
+use crate::error::{AdvanceError, LambdaStateIoSnafu, StateBusySnafu};
+use snafu::ResultExt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+
+/// Advisory `flock` lock on a lambda state, snapshot or lambda store, released when dropped
+///
+/// Lambda states are locked through a `.lock` sibling, as next lambda states are replaced by
+/// renames. Locks taken by other runners make acquiring fail right away. Lock files are only
+/// removed by the holder of an exclusive lock, and lockers check that the file they locked is
+/// still in place, so removing them doesn't let two runners hold the same lock.
+#[derive(Debug)]
+pub(crate) struct StateLock {
+    _file: File,
+    /// Lock file of a lambda state
+    lock_path: Option<String>,
+}
+
+impl StateLock {
+    /// Lock a lambda state file through its `.lock` sibling
+    pub fn lambda_state(path: &str, exclusive: bool) -> Result<Self, AdvanceError> {
+        let lock_path = format!("{path}.lock");
+        loop {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&lock_path)
+                .context(LambdaStateIoSnafu { path: &lock_path })?;
+            let file = Self::acquire(file, path, exclusive)?;
+            // The lock file may have been removed since it was opened
+            let locked = file
+                .metadata()
+                .context(LambdaStateIoSnafu { path: &lock_path })?;
+            match fs::metadata(&lock_path) {
+                Ok(current) if (current.dev(), current.ino()) == (locked.dev(), locked.ino()) => {
+                    return Ok(Self {
+                        _file: file,
+                        lock_path: Some(lock_path),
+                    });
+                }
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    return Err(err).context(LambdaStateIoSnafu { path: &lock_path });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Lock a directory itself, such as a snapshot or lambda store
+    pub fn directory(path: &str, exclusive: bool) -> Result<Self, AdvanceError> {
+        let file = File::open(path).context(LambdaStateIoSnafu { path })?;
+        Ok(Self {
+            _file: Self::acquire(file, path, exclusive)?,
+            lock_path: None,
+        })
+    }
+
+    /// Remove the lock file of an exclusive lambda state lock, then release the lock
+    pub fn remove(self) -> Result<(), AdvanceError> {
+        if let Some(lock_path) = &self.lock_path {
+            fs::remove_file(lock_path).context(LambdaStateIoSnafu { path: lock_path })?;
+        }
+        Ok(())
+    }
+
+    fn acquire(file: File, path: &str, exclusive: bool) -> Result<File, AdvanceError> {
+        let operation = if exclusive {
+            libc::LOCK_EX
+        } else {
+            libc::LOCK_SH
+        };
+        if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == -1 {
+            let err = io::Error::last_os_error();
+            snafu::ensure!(
+                err.raw_os_error() != Some(libc::EWOULDBLOCK),
+                StateBusySnafu { path }
+            );
+            return Err(err).context(LambdaStateIoSnafu { path });
+        }
+        Ok(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_it_fails_to_lock_busy_states() {
+        let path = std::env::temp_dir()
+            .join(format!("lambda-state-lock-{}", std::process::id()))
+            .display()
+            .to_string();
+
+        let shared = StateLock::lambda_state(&path, false).unwrap();
+        let other_shared = StateLock::lambda_state(&path, false).unwrap();
+        let err = StateLock::lambda_state(&path, true).unwrap_err();
+        assert!(matches!(err, AdvanceError::StateBusy { .. }));
+        drop((shared, other_shared));
+
+        let exclusive = StateLock::lambda_state(&path, true).unwrap();
+        let err = StateLock::lambda_state(&path, false).unwrap_err();
+        assert!(matches!(err, AdvanceError::StateBusy { .. }));
+        drop(exclusive);
+        StateLock::lambda_state(&path, true)
+            .unwrap()
+            .remove()
+            .unwrap();
+        assert!(!std::path::Path::new(&format!("{path}.lock")).exists());
+    }
+}
@@ -65,12 +65,37 @@ impl Tree {
         log2_word_size: usize,
         leaves: Level,
     ) -> Result<Self, Error> {
-        let max_len = 1 << (log2_root_size - log2_leaf_size);
-        snafu::ensure!(leaves.len() <= max_len, TooManyLeavesSnafu);
         let mut tree = Self::new(log2_root_size, log2_leaf_size, log2_word_size)?;
-        let level = tree.get_level_mut(log2_leaf_size).expect("cannot fail");
-        *level = leaves;
-        tree.bubble_up();
+        tree.set_leaves(leaves)?;
+        Ok(tree)
+    }
+
+    /// Create a new complete merkle tree whose pristine leaves have the given hash
+    ///
+    /// - `pristine_leaf`: Hash of a pristine leaf, instead of the hash of a zeroed word.
+    ///
+    /// For more information regarding the other parameters, see Tree::new_from_leaves().
+    pub fn new_from_leaves_with_pristine_leaf(
+        log2_root_size: usize,
+        log2_leaf_size: usize,
+        pristine_leaf: Hash,
+        leaves: Level,
+    ) -> Result<Self, Error> {
+        snafu::ensure!(
+            log2_leaf_size <= log2_root_size,
+            LeafSizeGreaterThanRootSizeSnafu
+        );
+        snafu::ensure!(
+            log2_root_size <= std::mem::size_of::<usize>() * 8,
+            TreeTooLargeSnafu
+        );
+        let mut tree = Self {
+            log2_root_size,
+            log2_leaf_size,
+            pristine: pristine::Tree::new_from_leaf(log2_root_size, log2_leaf_size, pristine_leaf)?,
+            tree: vec![vec![]; log2_root_size - log2_leaf_size + 1],
+        };
+        tree.set_leaves(leaves)?;
         Ok(tree)
     }
 
@@ -125,6 +150,38 @@ impl Tree {
             .len()
     }
 
+    /// Return the non-pristine leaf hashes
+    pub fn leaves(&self) -> &[Hash] {
+        self.get_level(self.log2_leaf_size).expect("cannot fail")
+    }
+
+    /// Drop all but the first `len` leaves
+    ///
+    /// - `len`: Number of leaves to keep.
+    pub fn truncate(&mut self, len: usize) {
+        let mut level_len = len;
+        for log2_size in self.log2_leaf_size..=self.log2_root_size {
+            self.get_level_mut(log2_size)
+                .expect("cannot fail")
+                .truncate(level_len);
+            level_len = level_len.div_ceil(2);
+        }
+        // The last entry of each level may have been built from a dropped node
+        self.bubble_up();
+    }
+
+    /// Replace the leaves of an empty tree
+    fn set_leaves(&mut self, leaves: Level) -> Result<(), Error> {
+        let max_len = 1 << (self.log2_root_size - self.log2_leaf_size);
+        snafu::ensure!(leaves.len() <= max_len, TooManyLeavesSnafu);
+        let level = self
+            .get_level_mut(self.log2_leaf_size)
+            .expect("cannot fail");
+        *level = leaves;
+        self.bubble_up();
+        Ok(())
+    }
+
     /// Return the hash of a node at a given address
     ///
     /// For more information regarding the other parameters, see Tree::get_proof().
@@ -435,6 +492,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_it_truncates_leaves() {
+        let leaves: Vec<Hash> = (0..5).map(|i| Hash::from([i; HASH_SIZE])).collect();
+        let mut tree = Tree::new_from_leaves(3, 0, 0, leaves.clone()).unwrap();
+        tree.truncate(3);
+        let expected = Tree::new_from_leaves(3, 0, 0, leaves[..3].to_vec()).unwrap();
+        assert_eq!(tree.leaves(), expected.leaves());
+        assert_eq!(tree.get_root_hash(), expected.get_root_hash());
+        tree.truncate(0);
+        compare_to_pristine(tree, 3, 0, 0);
+    }
+
     #[test]
     fn test_it_fails_to_push_leaf_in_full_tree() {
         let leaves = vec![Hash::from([0xFF; HASH_SIZE]); 8];
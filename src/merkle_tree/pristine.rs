@@ -24,18 +24,34 @@ impl Tree {
             log2_word_size <= log2_root_size,
             WordSizeGreaterThanRootSizeSnafu
         );
-        let num_hashes = log2_root_size - log2_word_size + 1;
-        let mut hashes = vec![];
-        let mut hasher = Hasher::new();
         let word: Vec<u8> = vec![0; 1 << log2_word_size];
-        hasher.update(&word);
-        hashes.push(hasher.finalize_reset().into());
+        let word_hash = Hasher::digest(&word).into();
+        Self::new_from_leaf(log2_root_size, log2_word_size, word_hash)
+    }
+
+    /// Create a new pristine merkle tree whose leaves have the given hash
+    ///
+    /// - `log2_root_size`: Log2 of the size in bytes of the whole merkle tree.
+    /// - `log2_leaf_size`: Log2 of the size in bytes of a single leaf.
+    /// - `leaf`: Hash of a pristine leaf.
+    pub fn new_from_leaf(
+        log2_root_size: usize,
+        log2_leaf_size: usize,
+        leaf: Hash,
+    ) -> Result<Self, Error> {
+        snafu::ensure!(
+            log2_leaf_size <= log2_root_size,
+            WordSizeGreaterThanRootSizeSnafu
+        );
+        let num_hashes = log2_root_size - log2_leaf_size + 1;
+        let mut hashes = vec![leaf];
+        let mut hasher = Hasher::new();
         for i in 1..num_hashes {
             hashes.push(get_concat_hash(&mut hasher, &hashes[i - 1], &hashes[i - 1]));
         }
         Ok(Self {
             log2_root_size,
-            log2_word_size,
+            log2_word_size: log2_leaf_size,
             hashes,
         })
     }
@@ -85,6 +101,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_it_creates_a_tree_from_a_pristine_leaf() {
+        let tree = Tree::new_from_leaf(2, 0, Hash::default()).unwrap();
+        assert_eq!(tree.get_hash(0).unwrap(), &Hash::default());
+        assert_eq!(
+            tree.get_hash(1).unwrap(),
+            &Hash::decode("ad3228b676f7d3cd4284a5443f17f1962b36e491b30a40b2405849e597ba5fb5")
+        );
+        assert_eq!(
+            tree.get_hash(2).unwrap(),
+            &Hash::decode("b4c11951957c6f8f642c4af61cd6b24640fec6dc7fc607ee8206a99e92410d30")
+        );
+    }
+
     #[test]
     fn test_it_creates_a_tree_with_correct_hashes() {
         let tree = Tree::new(8, 3).unwrap();
@@ -0,0 +1,200 @@
+use crate::hash::{Digest, Hash, Hasher};
+use crate::merkle_tree::{self, complete::Tree, proof::Proof};
+use alloy_primitives::{Address, U256};
+use alloy_sol_types::{sol, SolInterface};
+
+/// Log2 of the maximum number of outputs of an application
+pub const LOG2_MAX_OUTPUTS: usize = 63;
+
+/// Hash of an output, as a leaf of the outputs merkle tree
+pub fn output_hash(output: &[u8]) -> Hash {
+    Hasher::digest(output).into()
+}
+
+sol! { interface Outputs {
+    function Voucher(address destination, uint256 value, bytes calldata payload) external;
+    function Notice(bytes calldata payload) external;
+    function DelegateCallVoucher(address destination, bytes calldata payload) external;
+} }
+
+/// Output emitted by the guest, as defined by the Cartesi `Outputs` interface
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    Voucher {
+        destination: Address,
+        value: U256,
+        payload: Vec<u8>,
+    },
+    Notice {
+        payload: Vec<u8>,
+    },
+    DelegateCallVoucher {
+        destination: Address,
+        payload: Vec<u8>,
+    },
+}
+
+impl Output {
+    /// Decode the ABI-encoded output passed to the output callback
+    pub fn decode(data: &[u8]) -> Result<Self, alloy_sol_types::Error> {
+        Ok(match Outputs::OutputsCalls::abi_decode(data)? {
+            Outputs::OutputsCalls::Voucher(voucher) => Self::Voucher {
+                destination: voucher.destination,
+                value: voucher.value,
+                payload: voucher.payload.into(),
+            },
+            Outputs::OutputsCalls::Notice(notice) => Self::Notice {
+                payload: notice.payload.into(),
+            },
+            Outputs::OutputsCalls::DelegateCallVoucher(voucher) => Self::DelegateCallVoucher {
+                destination: voucher.destination,
+                payload: voucher.payload.into(),
+            },
+        })
+    }
+
+    /// ABI-encode the output as the guest emits it
+    pub fn encode(&self) -> Vec<u8> {
+        let call = match self.clone() {
+            Self::Voucher {
+                destination,
+                value,
+                payload,
+            } => Outputs::OutputsCalls::Voucher(Outputs::VoucherCall {
+                destination,
+                value,
+                payload: payload.into(),
+            }),
+            Self::Notice { payload } => Outputs::OutputsCalls::Notice(Outputs::NoticeCall {
+                payload: payload.into(),
+            }),
+            Self::DelegateCallVoucher {
+                destination,
+                payload,
+            } => Outputs::OutputsCalls::DelegateCallVoucher(Outputs::DelegateCallVoucherCall {
+                destination,
+                payload: payload.into(),
+            }),
+        };
+        call.abi_encode()
+    }
+}
+
+/// Merkle tree of the hashes of every output accepted so far, as kept by the guest
+///
+/// Leaves are addressed by output index and pristine leaves are zero.
+pub(crate) struct OutputsTree {
+    tree: Tree,
+}
+
+impl OutputsTree {
+    pub fn new(output_hashes: Vec<Hash>) -> Result<Self, merkle_tree::Error> {
+        Ok(Self {
+            tree: Tree::new_from_leaves_with_pristine_leaf(
+                LOG2_MAX_OUTPUTS,
+                0,
+                Hash::default(),
+                output_hashes,
+            )?,
+        })
+    }
+
+    pub fn push(&mut self, output: &[u8]) -> Result<(), merkle_tree::Error> {
+        self.tree.push(output_hash(output))
+    }
+
+    pub fn root_hash(&self) -> &Hash {
+        self.tree.get_root_hash()
+    }
+
+    /// Return the proof of the output at the given index
+    pub fn get_proof(&self, index: usize) -> Result<Proof, merkle_tree::Error> {
+        self.tree.get_proof(index, 0)
+    }
+
+    /// Hashes of the outputs in the tree, in order
+    pub fn output_hashes(&self) -> &[Hash] {
+        self.tree.leaves()
+    }
+
+    /// Drop the outputs past the first `len`, such as those of an input that wasn't accepted
+    pub fn truncate(&mut self, len: usize) {
+        self.tree.truncate(len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::address;
+    use alloy_sol_types::SolCall;
+
+    fn concat_hash(left: &Hash, right: &Hash) -> Hash {
+        Hasher::new()
+            .chain_update(left.data())
+            .chain_update(right.data())
+            .finalize()
+            .into()
+    }
+
+    #[test]
+    fn test_it_computes_the_root_hash_of_the_outputs() {
+        let outputs: [&[u8]; 3] = [b"first", b"second", b"third"];
+        let mut tree = OutputsTree::new(vec![output_hash(outputs[0])]).unwrap();
+        tree.push(outputs[1]).unwrap();
+        tree.push(outputs[2]).unwrap();
+
+        let mut zero = Hash::default();
+        let mut level: Vec<Hash> = outputs.iter().map(|output| output_hash(output)).collect();
+        for _ in 0..LOG2_MAX_OUTPUTS {
+            level = level
+                .chunks(2)
+                .map(|pair| concat_hash(&pair[0], pair.get(1).unwrap_or(&zero)))
+                .collect();
+            zero = concat_hash(&zero, &zero);
+        }
+        assert_eq!(tree.root_hash(), &level[0]);
+        assert_eq!(tree.output_hashes().len(), 3);
+
+        tree.truncate(1);
+        let expected = OutputsTree::new(vec![output_hash(outputs[0])]).unwrap();
+        assert_eq!(tree.root_hash(), expected.root_hash());
+    }
+
+    #[test]
+    fn test_it_decodes_and_encodes_outputs() {
+        let destination = address!("0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266");
+        let outputs = [
+            Output::Voucher {
+                destination,
+                value: U256::from(1000),
+                payload: vec![0xca, 0xfe],
+            },
+            Output::Notice {
+                payload: b"hello".to_vec(),
+            },
+            Output::DelegateCallVoucher {
+                destination,
+                payload: vec![],
+            },
+        ];
+        for output in outputs {
+            assert_eq!(Output::decode(&output.encode()).unwrap(), output);
+        }
+        let notice = Outputs::NoticeCall {
+            payload: vec![0xbe, 0xef].into(),
+        };
+        assert_eq!(
+            Output::decode(&notice.abi_encode()).unwrap(),
+            Output::Notice {
+                payload: vec![0xbe, 0xef]
+            }
+        );
+    }
+
+    #[test]
+    fn test_it_fails_to_decode_unknown_outputs() {
+        assert!(Output::decode(&[0xde, 0xad, 0xbe, 0xef]).is_err());
+        assert!(Output::decode(&[]).is_err());
+    }
+}
@@ -3,6 +3,9 @@
 
 use crate::hash::{Digest, Hash, Hasher};
 use crate::merkle_tree::{self, complete::Tree, proof::Proof};
+use crate::outputs::OutputsTree;
+use alloy_primitives::B256;
+use alloy_sol_types::sol;
 
 const LOG2_ROOT_SIZE: usize = 16 + LOG2_HASH_SIZE;
 const LOG2_WORD_SIZE: usize = 3;
@@ -37,3 +40,99 @@ pub fn compute_proofs(proofables: &mut [impl Proofable]) -> Result<Hash, merkle_
     }
     Ok(tree.get_root_hash().clone())
 }
+
+sol! {
+    /// Proof that an output is in the outputs merkle tree, as taken by
+    /// `CartesiApplication.validateOutput` and `executeOutput`
+    #[derive(Debug, PartialEq, Eq)]
+    struct OutputValidityProof {
+        uint64 outputIndex;
+        bytes32[] outputHashesSiblings;
+    }
+}
+
+impl From<&Proof> for OutputValidityProof {
+    fn from(proof: &Proof) -> Self {
+        Self {
+            outputIndex: proof.target_address as u64,
+            outputHashesSiblings: proof
+                .sibling_hashes
+                .iter()
+                .map(|hash| B256::from(*hash.data()))
+                .collect(),
+        }
+    }
+}
+
+/// Update the rollups v2 merkle proofs of every proofable in the array and return the merkle-tree's
+/// root hash
+///
+/// Unlike compute_proofs(), the hashes are the leaves themselves, in a tree of height 63 addressed
+/// by output index. Convert the proofs with `OutputValidityProof::from`.
+pub fn compute_output_validity_proofs(
+    proofables: &mut [impl Proofable],
+) -> Result<Hash, merkle_tree::Error> {
+    let leaves = proofables
+        .iter()
+        .map(|proofable| proofable.get_hash().clone())
+        .collect();
+    let tree = OutputsTree::new(leaves)?;
+    for (i, proofable) in proofables.iter_mut().enumerate() {
+        proofable.set_proof(tree.get_proof(i)?);
+    }
+    Ok(tree.root_hash().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outputs::{output_hash, LOG2_MAX_OUTPUTS};
+
+    struct Output {
+        hash: Hash,
+        proof: Option<Proof>,
+    }
+
+    impl Proofable for Output {
+        fn get_hash(&self) -> &Hash {
+            &self.hash
+        }
+
+        fn set_proof(&mut self, proof: Proof) {
+            self.proof = Some(proof);
+        }
+    }
+
+    #[test]
+    fn test_it_computes_output_validity_proofs() {
+        let mut outputs: Vec<Output> = [b"first", b"other", b"third"]
+            .iter()
+            .map(|output| Output {
+                hash: output_hash(*output),
+                proof: None,
+            })
+            .collect();
+        let root_hash = compute_output_validity_proofs(&mut outputs).unwrap();
+        let mut hasher = Hasher::new();
+        for (i, output) in outputs.iter().enumerate() {
+            let proof = OutputValidityProof::from(output.proof.as_ref().unwrap());
+            assert_eq!(proof.outputIndex, i as u64);
+            assert_eq!(proof.outputHashesSiblings.len(), LOG2_MAX_OUTPUTS);
+            let computed = proof.outputHashesSiblings.iter().enumerate().fold(
+                output.hash.clone(),
+                |node, (height, sibling)| {
+                    let sibling = Hash::from(sibling.0);
+                    let (left, right) = if (i >> height) & 1 == 0 {
+                        (&node, &sibling)
+                    } else {
+                        (&sibling, &node)
+                    };
+                    hasher.update(left.data());
+                    hasher.update(right.data());
+                    hasher.finalize_reset().into()
+                },
+            );
+            assert_eq!(computed, root_hash);
+        }
+    }
+}
@@ -0,0 +1,502 @@
+use crate::callbacks::{Callback, Callbacks};
+use crate::error::AdvanceError;
+use crate::hash::Hash;
+use crate::input::AdvanceInput;
+use crate::limits::RunLimits;
+use crate::lock::StateLock;
+use crate::outputs::OutputsTree;
+use crate::transition::{self, Transition};
+use crate::{
+    AdvanceOutcome, CancellationHandle, Console, InspectOutcome, LambdaStateRange,
+    RunAdvanceLambdaStatePaths, Session, StoredSnapshot, YieldManualReason,
+};
+use cartesi_machine::config::runtime::{
+    ConcurrencyRuntimeConfig, HTIFRuntimeConfig, RuntimeConfig,
+};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Settings of an `AdvanceRunner`
+pub struct RunnerConfig {
+    /// Directory of the machine snapshot every request starts from
+    pub machine_snapshot: String,
+    /// Lambda state files mapped into the machine
+    pub lambda_state_ranges: Vec<LambdaStateRange>,
+    /// Whether previous lambda states shorter than their memory range are mapped padded with zeros
+    pub pad_lambda_state: bool,
+    /// Whether to refuse running when lambda states can't be reflinked, instead of copying them
+    pub require_reflink: bool,
+    /// Journal of lambda state transitions, next to the first next lambda state by default
+    ///
+    /// Set a fixed one, such as `LambdaStore::journal_path`, when the next lambda states change
+    /// from input to input, so that interrupted transitions are found after a restart.
+    pub lambda_state_journal: Option<String>,
+    /// Whether the snapshot directory is locked together with the lambda states
+    pub lock_snapshot: bool,
+    /// Runtime configuration the snapshot is loaded with
+    pub runtime_config: RuntimeConfig,
+    /// Maximum number of machine cycles spent on a single request
+    pub cycle_limit: Option<u64>,
+    /// Maximum wall-clock time spent on a single request, loading and mapping included
+    pub timeout: Option<Duration>,
+    /// Handle used to cancel the request being processed
+    pub cancellation: Option<CancellationHandle>,
+    /// Where to store the machine after accepted advances, if anywhere
+    pub snapshot_storage: Option<SnapshotStorage>,
+}
+
+/// Storage of the machine snapshots taken after accepted advances
+#[derive(Debug, Clone)]
+pub struct SnapshotStorage {
+    /// Directory the snapshots are stored in, each in a subdirectory named after its input index
+    pub dir: String,
+    /// Whether to compute and store the root hash of each snapshot
+    pub root_hash: bool,
+}
+
+impl RunnerConfig {
+    /// Limits of a request coming in now
+    pub(crate) fn limits(&self) -> RunLimits {
+        RunLimits {
+            cycle_limit: self.cycle_limit,
+            deadline: self.timeout.map(|timeout| Instant::now() + timeout),
+            cancellation: self.cancellation.clone(),
+        }
+    }
+}
+
+/// Runs advance-state and inspect-state requests against a machine snapshot
+///
+/// `advance` and `inspect` load the snapshot anew for every request, while a `Session` keeps the
+/// machine alive between advances. Build one with `AdvanceRunner::builder`.
+pub struct AdvanceRunner {
+    pub(crate) config: RunnerConfig,
+    pub(crate) console: Console,
+    pub(crate) callbacks: Callbacks,
+    /// Outputs merkle tree of the machine, grown by accepted inputs
+    pub(crate) outputs_tree: OutputsTree,
+}
+
+impl AdvanceRunner {
+    pub fn builder(machine_snapshot: impl Into<String>) -> AdvanceRunnerBuilder {
+        AdvanceRunnerBuilder {
+            config: RunnerConfig {
+                machine_snapshot: machine_snapshot.into(),
+                lambda_state_ranges: vec![],
+                pad_lambda_state: false,
+                require_reflink: false,
+                lambda_state_journal: None,
+                lock_snapshot: false,
+                runtime_config: default_runtime_config(),
+                cycle_limit: None,
+                timeout: None,
+                cancellation: None,
+                snapshot_storage: None,
+            },
+            console: Console::Stdout,
+            callbacks: Callbacks::default(),
+            output_hashes: vec![],
+        }
+    }
+
+    pub fn config(&self) -> &RunnerConfig {
+        &self.config
+    }
+
+    /// Hashes of the outputs in the machine's outputs merkle tree, in order
+    pub fn output_hashes(&self) -> &[Hash] {
+        self.outputs_tree.output_hashes()
+    }
+
+    /// Change the lambda state used by the next requests
+    pub fn set_lambda_state(&mut self, lambda_state: Option<RunAdvanceLambdaStatePaths>) {
+        self.config.lambda_state_ranges = lambda_state.map(Into::into).into_iter().collect();
+    }
+
+    /// Change the lambda state ranges used by the next requests
+    pub fn set_lambda_state_ranges(&mut self, lambda_state_ranges: Vec<LambdaStateRange>) {
+        self.config.lambda_state_ranges = lambda_state_ranges;
+    }
+
+    /// Finish or undo the lambda state transition of an advance interrupted by a crash
+    ///
+    /// Call it when starting up, with the journal of the interrupted advance configured, or its
+    /// lambda state for the default journal; advances fail with `PendingLambdaStateJournal` until
+    /// then. `pending_journals` finds default journals left in a directory. A snapshot stored by
+    /// the advance is published or discarded with the lambda state, but isn't switched to.
+    /// Returns whether there was such a transition.
+    pub fn recover_lambda_state(&self) -> Result<bool, AdvanceError> {
+        match self.lambda_state_journal() {
+            Some(journal_path) => {
+                let _locks = self.lock_state(true)?;
+                transition::recover_journal(&journal_path)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Load the snapshot once for a stream of requests
+    ///
+    /// The previous lambda state is reflinked to the next one, which is mapped for the whole
+    /// session. The lambda states are locked until the session is dropped.
+    ///
+    /// Unlike `advance`, sessions write the next lambda state in place, without staging or
+    /// journaling it, so they aren't crash-safe: after a crash, the next lambda state holds an
+    /// arbitrary mix of the changes of the session's inputs and must be discarded. Resume from a
+    /// snapshot stored with `Session::store` instead.
+    pub fn session(&mut self) -> Result<Session<'_>, AdvanceError> {
+        let locks = self.lock_state(true)?;
+        let lambda_state_ranges = self.config.lambda_state_ranges.clone();
+        let mut session = Session::open(self, lambda_state_ranges, false, locks)?;
+        session.keep_checkpoints();
+        Ok(session)
+    }
+
+    /// Run an advance-state request on a freshly loaded snapshot
+    ///
+    /// The previous lambda state is reflinked to a temporary sibling of the next one, which the
+    /// guest then modifies. Only once the input is accepted is it synced and renamed to the next
+    /// lambda state, so that rejected inputs, exceptions, interrupted runs and crashes leave the
+    /// state as it was. Transitions are journaled for `recover_lambda_state`. The lambda states
+    /// are locked exclusively throughout, so other runners using them fail with `StateBusy`.
+    ///
+    /// With snapshot storage configured, accepted inputs store the machine in a new snapshot,
+    /// staged and published together with the lambda state so that neither gets ahead of the
+    /// other. The runner then switches to both: the snapshot becomes its machine snapshot and each
+    /// next lambda state its previous one, which the following advances update in place unless
+    /// new next lambda states are set. Otherwise the runner stays where it was, and the outputs of
+    /// the input are dropped from the outputs merkle tree like the rest of the machine state.
+    pub async fn advance(&mut self, input: AdvanceInput) -> Result<AdvanceOutcome, AdvanceError> {
+        let limits = self.config.limits();
+        let _locks = self.lock_state(true)?;
+        let output_count = self.outputs_tree.output_hashes().len();
+        let result = match self.lambda_state_journal() {
+            Some(journal_path) => self.advance_journaled(input, journal_path, &limits).await,
+            None => self.advance_staged(input, vec![], None, &limits).await,
+        };
+        match &result {
+            Ok(AdvanceOutcome {
+                stored_snapshot: Some(stored_snapshot),
+                ..
+            }) => {
+                self.config.machine_snapshot = stored_snapshot.path.clone();
+                for range in &mut self.config.lambda_state_ranges {
+                    range.lambda_state_previous_path = range.lambda_state_next_path.clone();
+                }
+            }
+            _ => self.outputs_tree.truncate(output_count),
+        }
+        result
+    }
+
+    /// Run an advance-state request within a journaled lambda state transition
+    async fn advance_journaled(
+        &mut self,
+        input: AdvanceInput,
+        journal_path: String,
+        limits: &RunLimits,
+    ) -> Result<AdvanceOutcome, AdvanceError> {
+        let (mut transition, staged_ranges) =
+            Transition::begin(journal_path, &self.config.lambda_state_ranges)?;
+        match self
+            .advance_staged(input, staged_ranges, Some(&mut transition), limits)
+            .await
+        {
+            Ok(outcome) if outcome.reason == YieldManualReason::Accepted => {
+                transition.commit()?;
+                Ok(outcome)
+            }
+            Ok(mut outcome) => {
+                transition.abort()?;
+                outcome.lambda_state_rolled_back = true;
+                Ok(outcome)
+            }
+            Err(err) => {
+                let _ = transition.abort();
+                Err(err)
+            }
+        }
+    }
+
+    /// Run an advance-state request with the given lambda state ranges
+    ///
+    /// With a transition, the snapshot of an accepted input is staged in it rather than stored in
+    /// place.
+    async fn advance_staged(
+        &mut self,
+        input: AdvanceInput,
+        lambda_state_ranges: Vec<LambdaStateRange>,
+        transition: Option<&mut Transition>,
+        limits: &RunLimits,
+    ) -> Result<AdvanceOutcome, AdvanceError> {
+        let snapshot_storage = self.config.snapshot_storage.clone();
+        let snapshot_path = snapshot_storage.as_ref().map(|snapshot_storage| {
+            Path::new(&snapshot_storage.dir)
+                .join(input.metadata.index.to_string())
+                .display()
+                .to_string()
+        });
+        let mut session = Session::open(self, lambda_state_ranges, false, vec![])?;
+        let mut outcome = session.advance_within(input, limits).await?;
+        outcome.stored_snapshot = match (snapshot_storage, snapshot_path) {
+            (Some(snapshot_storage), Some(path))
+                if outcome.reason == YieldManualReason::Accepted =>
+            {
+                let root_hash = if snapshot_storage.root_hash {
+                    Some(session.root_hash()?)
+                } else {
+                    None
+                };
+                let store_path = match transition {
+                    Some(transition) => transition.stage_snapshot(&path)?,
+                    None => path.clone(),
+                };
+                session.store(&store_path)?;
+                Some(StoredSnapshot { path, root_hash })
+            }
+            _ => None,
+        };
+        Ok(outcome)
+    }
+
+    /// Run an inspect-state query on a freshly loaded snapshot
+    ///
+    /// The previous lambda state (if any) is mapped privately, so whatever the guest writes to it
+    /// while handling the query is discarded together with the machine. Outputs are not allowed
+    /// during inspects and are ignored, while reports are returned. The previous lambda state is
+    /// locked shared, so inspects can run concurrently with each other but not with an advance
+    /// from it.
+    pub async fn inspect(&mut self, query: Vec<u8>) -> Result<InspectOutcome, AdvanceError> {
+        let limits = self.config.limits();
+        let locks = self.lock_state(false)?;
+        let lambda_state_ranges = self.config.lambda_state_ranges.clone();
+        Session::open(self, lambda_state_ranges, true, locks)?
+            .inspect_within(query, &limits)
+            .await
+    }
+
+    /// Lock the lambda states (and the snapshot, if configured) against other runners
+    ///
+    /// Exclusive locks cover the previous and next lambda states, shared ones only the previous.
+    fn lock_state(&self, exclusive: bool) -> Result<Vec<StateLock>, AdvanceError> {
+        let mut locks = vec![];
+        if self.config.lock_snapshot {
+            locks.push(StateLock::directory(
+                &self.config.machine_snapshot,
+                exclusive,
+            )?);
+        }
+        let mut paths = BTreeSet::new();
+        for range in &self.config.lambda_state_ranges {
+            paths.insert(&range.lambda_state_previous_path);
+            if exclusive {
+                paths.insert(&range.lambda_state_next_path);
+            }
+        }
+        for path in paths {
+            locks.push(StateLock::lambda_state(path, exclusive)?);
+        }
+        Ok(locks)
+    }
+
+    fn lambda_state_journal(&self) -> Option<String> {
+        let first_range = self.config.lambda_state_ranges.first()?;
+        Some(
+            self.config
+                .lambda_state_journal
+                .clone()
+                .unwrap_or_else(|| format!("{}.journal", first_range.lambda_state_next_path)),
+        )
+    }
+}
+
+/// Builder of `AdvanceRunner`s
+pub struct AdvanceRunnerBuilder {
+    config: RunnerConfig,
+    console: Console,
+    callbacks: Callbacks,
+    output_hashes: Vec<Hash>,
+}
+
+impl AdvanceRunnerBuilder {
+    /// Map a single lambda state file, shared, at the default memory range
+    pub fn lambda_state(mut self, lambda_state: RunAdvanceLambdaStatePaths) -> Self {
+        self.config.lambda_state_ranges = vec![lambda_state.into()];
+        self
+    }
+
+    /// Add a lambda state file mapped over its own memory range
+    pub fn lambda_state_range(mut self, range: LambdaStateRange) -> Self {
+        self.config.lambda_state_ranges.push(range);
+        self
+    }
+
+    /// Accept previous lambda states shorter than their memory range, padding them with zeros
+    ///
+    /// The padding goes to the next lambda state, or to a private copy for inspects; previous
+    /// lambda states are left untouched.
+    pub fn pad_lambda_state(mut self, pad: bool) -> Self {
+        self.config.pad_lambda_state = pad;
+        self
+    }
+
+    /// Set where lambda state transitions are journaled
+    ///
+    /// See `RunnerConfig::lambda_state_journal`.
+    pub fn lambda_state_journal(mut self, path: impl Into<String>) -> Self {
+        self.config.lambda_state_journal = Some(path.into());
+        self
+    }
+
+    /// Lock the snapshot directory together with the lambda states
+    pub fn lock_snapshot(mut self, lock_snapshot: bool) -> Self {
+        self.config.lock_snapshot = lock_snapshot;
+        self
+    }
+
+    /// Refuse to run when the previous lambda state can't be reflinked to the next one
+    ///
+    /// Otherwise it's copied, preserving holes.
+    pub fn require_reflink(mut self, require_reflink: bool) -> Self {
+        self.config.require_reflink = require_reflink;
+        self
+    }
+
+    /// Override the runtime configuration the snapshot is loaded with
+    ///
+    /// The HTIF console settings are always derived from the configured `Console`.
+    pub fn runtime_config(mut self, runtime_config: RuntimeConfig) -> Self {
+        self.config.runtime_config = runtime_config;
+        self
+    }
+
+    pub fn console(mut self, console: Console) -> Self {
+        self.console = console;
+        self
+    }
+
+    pub fn cycle_limit(mut self, cycle_limit: u64) -> Self {
+        self.config.cycle_limit = Some(cycle_limit);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = Some(timeout);
+        self
+    }
+
+    /// Cancel requests through the given handle
+    ///
+    /// The handle stays cancelled across requests until it's reset.
+    pub fn cancellation(mut self, cancellation: CancellationHandle) -> Self {
+        self.config.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Store the machine after every accepted advance in a subdirectory of `dir`
+    ///
+    /// With `root_hash`, the root hash of each snapshot is computed, stored alongside it and
+    /// returned in the outcome.
+    pub fn store_snapshots(mut self, dir: impl Into<String>, root_hash: bool) -> Self {
+        self.config.snapshot_storage = Some(SnapshotStorage {
+            dir: dir.into(),
+            root_hash,
+        });
+        self
+    }
+
+    /// Set the hashes of the outputs the snapshot already holds, in order
+    ///
+    /// The outputs root hash reported by the guest on acceptance is checked against a tree of these
+    /// hashes followed by those of the new outputs.
+    pub fn output_hashes(mut self, output_hashes: Vec<Hash>) -> Self {
+        self.output_hashes = output_hashes;
+        self
+    }
+
+    pub fn report_callback(
+        mut self,
+        callback: impl FnMut(u16, &[u8]) -> Result<(), Box<dyn Error>> + 'static,
+    ) -> Self {
+        self.callbacks.report = Some(Box::new(callback));
+        self
+    }
+
+    pub fn output_callback(
+        mut self,
+        callback: impl FnMut(u16, &[u8]) -> Result<(), Box<dyn Error>> + 'static,
+    ) -> Self {
+        self.callbacks.output = Some(Box::new(callback));
+        self
+    }
+
+    /// Set the callback called when the guest accepts, rejects or raises an exception
+    ///
+    /// It receives the outputs root hash on acceptance and the message on exceptions.
+    pub fn finish_callback(
+        mut self,
+        callback: impl FnMut(u16, &[u8]) -> Result<(), Box<dyn Error>> + 'static,
+    ) -> Self {
+        self.callbacks.finish = Some(Box::new(callback));
+        self
+    }
+
+    pub fn progress_callback(mut self, callback: impl FnMut(u32) + 'static) -> Self {
+        self.callbacks.progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Register the callback for the automatic yields of a domain
+    pub fn automatic_gio_callback(
+        mut self,
+        domain: u16,
+        callback: impl FnMut(u16, &[u8]) -> Result<(), Box<dyn Error>> + 'static,
+    ) -> Self {
+        self.callbacks
+            .automatic_gio
+            .insert(domain, Box::new(callback));
+        self
+    }
+
+    /// Register the handler for the GIO requests of a domain
+    pub fn gio_callback(mut self, domain: u16, callback: Callback) -> Self {
+        self.callbacks.gio.insert(domain, callback);
+        self
+    }
+
+    pub fn build(mut self) -> AdvanceRunner {
+        self.config.runtime_config.htif = Some(HTIFRuntimeConfig {
+            no_console_putchar: Some(matches!(self.console, Console::Silent)),
+        });
+        if self
+            .config
+            .snapshot_storage
+            .as_ref()
+            .is_some_and(|snapshot_storage| snapshot_storage.root_hash)
+        {
+            self.config.runtime_config.skip_root_hash_store = Some(false);
+        }
+        AdvanceRunner {
+            config: self.config,
+            console: self.console,
+            callbacks: self.callbacks,
+            // A vector can't hold more hashes than the tree has leaves
+            outputs_tree: OutputsTree::new(self.output_hashes).expect("cannot fail"),
+        }
+    }
+}
+
+fn default_runtime_config() -> RuntimeConfig {
+    RuntimeConfig {
+        skip_root_hash_check: Some(true),
+        skip_root_hash_store: Some(true),
+        concurrency: Some(ConcurrencyRuntimeConfig {
+            update_merkle_tree: Some(0),
+        }),
+        htif: None,
+        skip_version_check: Some(false),
+        soft_yield: Some(false),
+    }
+}
@@ -0,0 +1,420 @@
+use crate::cmio::{expect_rx_accepted, run_until_finished, send_request, ConsoleSink, RunRecord};
+use crate::error::{
+    AdvanceError, LambdaStateIoSnafu, LoadSnapshotSnafu, MachineSnafu, MapLambdaStateSnafu,
+    MissingMemoryRangeSnafu, SessionUnusableSnafu, StoreSnapshotSnafu,
+};
+use crate::hash::Hash;
+use crate::input::{encode_evm_advance, AdvanceInput};
+use crate::lambda_state::{clone_lambda_state, pad, padded_copy, preflight};
+use crate::limits::RunLimits;
+use crate::lock::StateLock;
+use crate::{
+    AdvanceOutcome, AdvanceRunner, Console, InspectOutcome, LambdaStateRange, RunStats,
+    RunnerConfig, YieldManualReason,
+};
+use alloy_primitives::U256;
+use cartesi_machine::{machine::Machine, types::cmio::CmioResponseReason};
+use snafu::{OptionExt, ResultExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Machine kept alive across requests
+///
+/// The snapshot is loaded once and every accepted input continues from the state the previous
+/// one left the machine in. The next lambda state is mapped for the whole session, so it
+/// accumulates the changes of every accepted advance. Open one with `AdvanceRunner::session`.
+///
+/// The bindings can't fork a machine, so sessions keep a checkpoint of the machine and lambda
+/// states as of the latest accepted input, and reload it after inspects and inputs that aren't
+/// accepted. The checkpoint is stored before the first request following an accepted input, in a
+/// `.checkpoint-*` sibling of the snapshot and `.checkpoint` siblings of the next lambda states.
+pub struct Session<'a> {
+    runner: &'a mut AdvanceRunner,
+    machine: Machine,
+    lambda_state_ranges: Vec<LambdaStateRange>,
+    /// State requests are reverted to, for sessions that outlive a request
+    checkpoint: Option<Checkpoint>,
+    /// Whether the machine is waiting for a new request
+    usable: bool,
+    /// Time spent loading the snapshot, reported with the first request
+    load_time: Option<Duration>,
+    /// Time spent mapping the lambda state, reported with the first request
+    replace_time: Option<Duration>,
+    /// Locks on the snapshot and lambda states, held as long as the machine
+    _locks: Vec<StateLock>,
+}
+
+/// Machine and lambda states as of the latest accepted input of a session
+struct Checkpoint {
+    /// Directory the machine is stored in when the checkpoint moves
+    dir: String,
+    snapshot: String,
+    /// Lambda state of each range
+    lambda_states: Vec<String>,
+    /// Whether the machine has accepted inputs since the checkpoint was taken
+    stale: bool,
+}
+
+impl<'a> Session<'a> {
+    /// Load the runner's snapshot and map its lambda state
+    ///
+    /// Each previous lambda state is checked against the memory range it's mapped over first.
+    /// With `read_only`, the previous lambda state is mapped privately instead of being
+    /// reflinked to the next one, through a padded copy if it's short. `locks` are released when
+    /// the session is dropped.
+    pub(crate) fn open(
+        runner: &'a mut AdvanceRunner,
+        lambda_state_ranges: Vec<LambdaStateRange>,
+        read_only: bool,
+        locks: Vec<StateLock>,
+    ) -> Result<Self, AdvanceError> {
+        let (machine, load_time, replace_time) = load(
+            &runner.config,
+            &runner.config.machine_snapshot,
+            &lambda_state_ranges,
+            read_only,
+        )?;
+        Ok(Self {
+            runner,
+            machine,
+            lambda_state_ranges,
+            checkpoint: None,
+            usable: true,
+            load_time: Some(load_time),
+            replace_time,
+            _locks: locks,
+        })
+    }
+
+    /// Revert to the state as of the latest accepted input after every request that didn't
+    /// advance it, instead of becoming unusable
+    pub(crate) fn keep_checkpoints(&mut self) {
+        static CHECKPOINTS: AtomicU64 = AtomicU64::new(0);
+        let snapshot = self.runner.config.machine_snapshot.clone();
+        self.checkpoint = Some(Checkpoint {
+            dir: format!(
+                "{snapshot}.checkpoint-{}-{}",
+                std::process::id(),
+                CHECKPOINTS.fetch_add(1, Ordering::Relaxed)
+            ),
+            snapshot,
+            lambda_states: self
+                .lambda_state_ranges
+                .iter()
+                .map(|range| range.lambda_state_previous_path.clone())
+                .collect(),
+            stale: false,
+        });
+    }
+
+    /// Run an advance-state request
+    ///
+    /// The outcome doesn't cover the lambda state or snapshots, which sessions don't manage per
+    /// input.
+    pub async fn advance(&mut self, input: AdvanceInput) -> Result<AdvanceOutcome, AdvanceError> {
+        let limits = self.runner.config.limits();
+        self.advance_within(input, &limits).await
+    }
+
+    /// Run an advance-state request within limits taken when the request came in
+    pub(crate) async fn advance_within(
+        &mut self,
+        input: AdvanceInput,
+        limits: &RunLimits,
+    ) -> Result<AdvanceOutcome, AdvanceError> {
+        self.ensure_usable()?;
+        self.update_checkpoint()?;
+        let encoded = encode_evm_advance(&input.metadata, input.payload);
+        send_request(&mut self.machine, CmioResponseReason::Advance, &encoded)?;
+        self.run(Some(input.metadata.index), limits).await
+    }
+
+    /// Run an inspect-state query
+    ///
+    /// Outputs are not allowed during inspects and are ignored, while reports are returned.
+    /// Whatever the guest writes is reverted afterwards.
+    pub async fn inspect(&mut self, query: Vec<u8>) -> Result<InspectOutcome, AdvanceError> {
+        let limits = self.runner.config.limits();
+        self.inspect_within(query, &limits).await
+    }
+
+    /// Run an inspect-state query within limits taken when the request came in
+    pub(crate) async fn inspect_within(
+        &mut self,
+        query: Vec<u8>,
+        limits: &RunLimits,
+    ) -> Result<InspectOutcome, AdvanceError> {
+        self.ensure_usable()?;
+        self.update_checkpoint()?;
+        send_request(&mut self.machine, CmioResponseReason::Inspect, &query)?;
+        let outcome = self.run(None, limits).await?;
+        Ok(InspectOutcome {
+            reason: outcome.reason,
+            exception_message: outcome.exception_message,
+            reports: outcome.reports,
+            stats: outcome.stats,
+        })
+    }
+
+    /// Store the current machine state as a snapshot in `dir`
+    ///
+    /// The next lambda states aren't part of it, and have to be copied alongside it to resume
+    /// from this point.
+    pub fn store(&mut self, dir: impl AsRef<Path>) -> Result<(), AdvanceError> {
+        let dir = dir.as_ref();
+        self.machine.store(dir).context(StoreSnapshotSnafu {
+            path: dir.display().to_string(),
+        })
+    }
+
+    /// Compute the root hash of the current machine state
+    pub fn root_hash(&mut self) -> Result<Hash, AdvanceError> {
+        self.machine
+            .root_hash()
+            .map(Hash::from)
+            .context(MachineSnafu)
+    }
+
+    /// Run the machine until it finishes the current request
+    ///
+    /// Advances, identified by their input index, push their outputs to the runner's outputs
+    /// tree, which is rolled back unless the input is accepted. Sessions keeping checkpoints then
+    /// revert every request but accepted advances.
+    async fn run(
+        &mut self,
+        input_index: Option<U256>,
+        limits: &RunLimits,
+    ) -> Result<AdvanceOutcome, AdvanceError> {
+        let runner = &mut *self.runner;
+        let output_count = runner.outputs_tree.output_hashes().len();
+        let outputs_tree = input_index.map(|_| &mut runner.outputs_tree);
+        let mut console_sink = console_sink(&mut runner.console, input_index);
+        let mut record = RunRecord::default();
+        let mcycle_start = self.machine.mcycle().context(MachineSnafu)?;
+        let started_at = Instant::now();
+        let result = run_until_finished(
+            &mut self.machine,
+            &mut runner.callbacks,
+            outputs_tree,
+            limits,
+            &mut console_sink,
+            &mut record,
+        )
+        .await;
+        let run_time = started_at.elapsed();
+        drop(console_sink);
+        let advanced = input_index.is_some() && matches!(result, Ok(YieldManualReason::Accepted));
+        if !matches!(result, Ok(YieldManualReason::Accepted)) {
+            runner.outputs_tree.truncate(output_count);
+        }
+        let mcycle_end = self.machine.mcycle().context(MachineSnafu);
+        let lambda_state_rolled_back =
+            input_index.is_some() && !advanced && self.checkpoint.is_some();
+        if let (Some(checkpoint), true) = (&mut self.checkpoint, advanced) {
+            checkpoint.stale = true;
+        } else if self.checkpoint.is_some() {
+            let reverted = self.revert();
+            self.usable = reverted.is_ok();
+            if result.is_ok() {
+                reverted?;
+            }
+        } else {
+            self.usable = advanced;
+        }
+        let reason = result?;
+        let stats = RunStats {
+            mcycle_start,
+            mcycle_end: mcycle_end?,
+            gio_round_trips: record.gio_round_trips,
+            outputs: record.outputs.len() as u64,
+            reports: record.reports.len() as u64,
+            load_time: self.load_time.take(),
+            replace_time: self.replace_time.take(),
+            run_time,
+        };
+        Ok(AdvanceOutcome {
+            reason,
+            exception_message: record.exception_message,
+            outputs: record.outputs,
+            reports: record.reports,
+            outputs_root_hash: record.outputs_root_hash,
+            stats,
+            lambda_state_rolled_back,
+            stored_snapshot: None,
+        })
+    }
+
+    /// Store the machine and lambda states as the checkpoint, if inputs were accepted since the
+    /// current one was taken
+    ///
+    /// The session can't revert requests once this fails, so it becomes unusable.
+    fn update_checkpoint(&mut self) -> Result<(), AdvanceError> {
+        if !self
+            .checkpoint
+            .as_ref()
+            .is_some_and(|checkpoint| checkpoint.stale)
+        {
+            return Ok(());
+        }
+        let stored = self.store_checkpoint();
+        self.usable = stored.is_ok();
+        stored
+    }
+
+    fn store_checkpoint(&mut self) -> Result<(), AdvanceError> {
+        let checkpoint = self
+            .checkpoint
+            .as_mut()
+            .expect("only called with a checkpoint");
+        remove_checkpoint(checkpoint)?;
+        self.machine
+            .store(Path::new(&checkpoint.dir))
+            .context(StoreSnapshotSnafu {
+                path: &checkpoint.dir,
+            })?;
+        checkpoint.snapshot = checkpoint.dir.clone();
+        checkpoint.lambda_states.clear();
+        for range in &self.lambda_state_ranges {
+            let next_path = &range.lambda_state_next_path;
+            let lambda_state = format!("{next_path}.checkpoint");
+            clone_lambda_state(next_path, &lambda_state, self.runner.config.require_reflink)?;
+            checkpoint.lambda_states.push(lambda_state);
+        }
+        checkpoint.stale = false;
+        Ok(())
+    }
+
+    /// Reload the checkpoint, discarding what the machine did since
+    fn revert(&mut self) -> Result<(), AdvanceError> {
+        let checkpoint = self
+            .checkpoint
+            .as_ref()
+            .expect("only called with a checkpoint");
+        let lambda_state_ranges: Vec<_> = self
+            .lambda_state_ranges
+            .iter()
+            .zip(&checkpoint.lambda_states)
+            .map(|(range, lambda_state)| LambdaStateRange {
+                lambda_state_previous_path: lambda_state.clone(),
+                ..range.clone()
+            })
+            .collect();
+        let config = &self.runner.config;
+        self.machine = load(config, &checkpoint.snapshot, &lambda_state_ranges, false)?.0;
+        Ok(())
+    }
+
+    /// Check that the machine is waiting for a new request
+    ///
+    /// Rejections, exceptions, interrupted runs and failures leave the machine in a state it
+    /// can't take new requests from, unless it's reverted to a checkpoint.
+    fn ensure_usable(&self) -> Result<(), AdvanceError> {
+        snafu::ensure!(self.usable, SessionUnusableSnafu);
+        Ok(())
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        if let Some(checkpoint) = &self.checkpoint {
+            let _ = remove_checkpoint(checkpoint);
+        }
+    }
+}
+
+/// Load a snapshot and map lambda states into it
+///
+/// Returns the machine with the time spent loading it and mapping the lambda states. See
+/// `Session::open`.
+fn load(
+    config: &RunnerConfig,
+    snapshot: &str,
+    lambda_state_ranges: &[LambdaStateRange],
+    read_only: bool,
+) -> Result<(Machine, Duration, Option<Duration>), AdvanceError> {
+    let load_started_at = Instant::now();
+    let mut machine = Machine::load(Path::new(snapshot), &config.runtime_config)
+        .context(LoadSnapshotSnafu { path: snapshot })?;
+    let load_time = load_started_at.elapsed();
+
+    let memory_ranges = if lambda_state_ranges.is_empty() {
+        vec![]
+    } else {
+        machine.memory_ranges().context(MachineSnafu)?
+    };
+    let mut replace_time = None;
+    for range in lambda_state_ranges {
+        let range_length = memory_ranges
+            .iter()
+            .find(|memory_range| memory_range.start == range.start)
+            .map(|memory_range| memory_range.length)
+            .context(MissingMemoryRangeSnafu { start: range.start })?;
+        let previous_path = &range.lambda_state_previous_path;
+        let needs_padding = preflight(previous_path, range_length, config.pad_lambda_state)?;
+        let (image_path, shared) = if !read_only {
+            let next_path = &range.lambda_state_next_path;
+            clone_lambda_state(previous_path, next_path, config.require_reflink)?;
+            if needs_padding {
+                pad(next_path, range_length)?;
+            }
+            (next_path.clone(), range.shared)
+        } else if needs_padding {
+            (padded_copy(previous_path, range_length)?, false)
+        } else {
+            (previous_path.clone(), false)
+        };
+
+        let replace_started_at = Instant::now();
+        let mapped = machine
+            .replace_memory_range(
+                range.start,
+                range_length,
+                shared,
+                Some(Path::new(&image_path)),
+            )
+            .context(MapLambdaStateSnafu);
+        *replace_time.get_or_insert(Duration::ZERO) += replace_started_at.elapsed();
+        if read_only && needs_padding {
+            // The copy is mapped privately, so it's no longer needed once mapped
+            std::fs::remove_file(&image_path).context(LambdaStateIoSnafu { path: &image_path })?;
+        }
+        mapped?;
+    }
+    expect_rx_accepted(&mut machine)?;
+    Ok((machine, load_time, replace_time))
+}
+
+/// Remove the files of a checkpoint stored by the session
+///
+/// The initial checkpoint is the runner's snapshot and previous lambda states, which are kept.
+fn remove_checkpoint(checkpoint: &Checkpoint) -> Result<(), AdvanceError> {
+    if checkpoint.snapshot != checkpoint.dir {
+        return Ok(());
+    }
+    match std::fs::remove_dir_all(&checkpoint.dir) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            return Err(err).context(LambdaStateIoSnafu {
+                path: &checkpoint.dir,
+            });
+        }
+        _ => {}
+    }
+    for lambda_state in &checkpoint.lambda_states {
+        match std::fs::remove_file(lambda_state) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(err).context(LambdaStateIoSnafu { path: lambda_state });
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Tag the captured console output of a run with the index of its input
+fn console_sink(console: &mut Console, input_index: Option<U256>) -> Option<ConsoleSink<'_>> {
+    match console {
+        Console::Capture(callback) => Some(Box::new(move |data| callback(input_index, data))),
+        Console::Stdout | Console::Silent => None,
+    }
+}
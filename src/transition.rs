@@ -0,0 +1,292 @@
+use crate::error::{
+    AdvanceError, InvalidLambdaStateJournalSnafu, LambdaStateIoSnafu,
+    PendingLambdaStateJournalSnafu,
+};
+use crate::lock::StateLock;
+use crate::LambdaStateRange;
+use snafu::{OptionExt, ResultExt};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Line of the journal marking a transition whose staged files are complete
+const COMMIT_MARKER: &str = "commit";
+
+/// Lambda state transition of an advance
+///
+/// The next lambda states are staged in temporary siblings and only renamed into place once the
+/// input is accepted, together with the snapshot stored after it, if any. A journal lists the
+/// staged paths: until it holds the commit marker they are discarded on recovery, and from then on
+/// they are published. The journal is locked while the transition runs, so it can't be recovered
+/// from under a live runner.
+pub(crate) struct Transition {
+    journal_path: String,
+    _lock: StateLock,
+    /// Pairs of staged and next paths, of lambda state files or snapshot directories
+    staged: Vec<(String, String)>,
+}
+
+impl Transition {
+    /// Journal the transition and return the ranges mapping the staged files
+    ///
+    /// Fails if the journal holds a transition that was interrupted and not recovered yet.
+    pub fn begin(
+        journal_path: String,
+        lambda_state_ranges: &[LambdaStateRange],
+    ) -> Result<(Self, Vec<LambdaStateRange>), AdvanceError> {
+        let lock = StateLock::lambda_state(&journal_path, true)?;
+        let mut staged = vec![];
+        let mut staged_ranges = vec![];
+        for range in lambda_state_ranges {
+            let staged_path = format!("{}.partial", range.lambda_state_next_path);
+            staged.push((staged_path.clone(), range.lambda_state_next_path.clone()));
+            staged_ranges.push(LambdaStateRange {
+                lambda_state_next_path: staged_path,
+                ..range.clone()
+            });
+        }
+        let journal = staged
+            .iter()
+            .map(|(staged_path, next_path)| format!("{staged_path}\t{next_path}\n"))
+            .collect::<String>();
+        match write_synced(&journal_path, journal.as_bytes()) {
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                return PendingLambdaStateJournalSnafu { path: journal_path }.fail();
+            }
+            result => result.context(LambdaStateIoSnafu {
+                path: &journal_path,
+            })?,
+        }
+        Ok((
+            Self {
+                journal_path,
+                _lock: lock,
+                staged,
+            },
+            staged_ranges,
+        ))
+    }
+
+    /// Journal a snapshot directory to publish together with the lambda states
+    ///
+    /// Returns the path to store the snapshot at until then.
+    pub fn stage_snapshot(&mut self, snapshot_path: &str) -> Result<String, AdvanceError> {
+        let staged_path = format!("{snapshot_path}.partial");
+        remove_staged(&staged_path).context(LambdaStateIoSnafu { path: &staged_path })?;
+        self.append_to_journal(&format!("{staged_path}\t{snapshot_path}"))?;
+        self.staged
+            .push((staged_path.clone(), snapshot_path.to_string()));
+        Ok(staged_path)
+    }
+
+    /// Make the staged files durable and rename them into place
+    pub fn commit(self) -> Result<(), AdvanceError> {
+        for (staged_path, _) in &self.staged {
+            sync_staged(Path::new(staged_path))
+                .context(LambdaStateIoSnafu { path: staged_path })?;
+        }
+        self.append_to_journal(COMMIT_MARKER)?;
+        publish(&self.staged)?;
+        remove_journal(&self.journal_path)
+    }
+
+    /// Discard the staged files
+    pub fn abort(self) -> Result<(), AdvanceError> {
+        discard(&self.staged)?;
+        remove_journal(&self.journal_path)
+    }
+
+    fn append_to_journal(&self, line: &str) -> Result<(), AdvanceError> {
+        OpenOptions::new()
+            .append(true)
+            .open(&self.journal_path)
+            .and_then(|mut file| {
+                writeln!(file, "{line}")?;
+                file.sync_all()
+            })
+            .context(LambdaStateIoSnafu {
+                path: &self.journal_path,
+            })
+    }
+}
+
+/// Finish or undo the transition left in a journal by an interrupted runner
+///
+/// Returns whether there was one. Fails with `StateBusy` while a runner is using the journal.
+pub fn recover_journal(journal_path: &str) -> Result<bool, AdvanceError> {
+    let _lock = StateLock::lambda_state(journal_path, true)?;
+    let journal = match fs::read_to_string(journal_path) {
+        Ok(journal) => journal,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err).context(LambdaStateIoSnafu { path: journal_path }),
+    };
+    let mut staged = vec![];
+    let mut committed = false;
+    for line in journal.lines() {
+        if line == COMMIT_MARKER {
+            committed = true;
+            continue;
+        }
+        let (staged_path, next_path) = line
+            .split_once('\t')
+            .context(InvalidLambdaStateJournalSnafu { path: journal_path })?;
+        staged.push((staged_path.to_string(), next_path.to_string()));
+    }
+    if committed {
+        publish(&staged)?;
+    } else {
+        discard(&staged)?;
+    }
+    remove_journal(journal_path)?;
+    Ok(true)
+}
+
+/// List the journals in `dir`, which hold transitions that were interrupted unless a runner is
+/// using them
+///
+/// Journals are recognized by their `.journal` extension, as for the default journal of a runner.
+pub fn pending_journals(dir: impl AsRef<Path>) -> Result<Vec<String>, AdvanceError> {
+    let dir = dir.as_ref();
+    let path = dir.display().to_string();
+    let mut journals = vec![];
+    for entry in fs::read_dir(dir).context(LambdaStateIoSnafu { path: &path })? {
+        let entry_path = entry.context(LambdaStateIoSnafu { path: &path })?.path();
+        if entry_path
+            .extension()
+            .is_some_and(|extension| extension == "journal")
+        {
+            journals.push(entry_path.display().to_string());
+        }
+    }
+    journals.sort();
+    Ok(journals)
+}
+
+/// Rename the staged files that are still around into place
+///
+/// Directories left at the next path, as by a publication cut short, are replaced.
+fn publish(staged: &[(String, String)]) -> Result<(), AdvanceError> {
+    for (staged_path, next_path) in staged {
+        if Path::new(staged_path).is_dir() && Path::new(next_path).is_dir() {
+            fs::remove_dir_all(next_path).context(LambdaStateIoSnafu { path: next_path })?;
+        }
+        match fs::rename(staged_path, next_path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                return Err(err).context(LambdaStateIoSnafu { path: next_path });
+            }
+            _ => {}
+        }
+        sync_parent(next_path).context(LambdaStateIoSnafu { path: next_path })?;
+    }
+    Ok(())
+}
+
+fn discard(staged: &[(String, String)]) -> Result<(), AdvanceError> {
+    for (staged_path, _) in staged {
+        remove_staged(staged_path).context(LambdaStateIoSnafu { path: staged_path })?;
+    }
+    Ok(())
+}
+
+/// Remove a staged file or directory, if it's there
+fn remove_staged(path: &str) -> io::Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(err) => Err(err),
+    };
+    match result {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Make a staged file, or a staged directory and everything in it, durable
+fn sync_staged(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            sync_staged(&entry?.path())?;
+        }
+    }
+    File::open(path)?.sync_all()
+}
+
+fn remove_journal(journal_path: &str) -> Result<(), AdvanceError> {
+    fs::remove_file(journal_path)
+        .and_then(|_| sync_parent(journal_path))
+        .context(LambdaStateIoSnafu { path: journal_path })
+}
+
+/// Write a new file and make it durable, failing if it exists
+fn write_synced(path: &str, contents: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    sync_parent(path)
+}
+
+/// Make the creation, rename or removal of an entry of a directory durable
+pub(crate) fn sync_parent(path: &str) -> io::Result<()> {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_it_recovers_interrupted_transitions() {
+        let dir = std::env::temp_dir().join(format!("transition-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).display().to_string();
+        let journal_path = path("next.journal");
+        let ranges = [LambdaStateRange {
+            start: 0,
+            lambda_state_previous_path: path("previous"),
+            lambda_state_next_path: path("next"),
+            shared: true,
+        }];
+
+        let (_, staged_ranges) = Transition::begin(journal_path.clone(), &ranges).unwrap();
+        let staged_path = &staged_ranges[0].lambda_state_next_path;
+        fs::write(staged_path, b"half written").unwrap();
+        let err = Transition::begin(journal_path.clone(), &ranges)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            AdvanceError::PendingLambdaStateJournal { .. }
+        ));
+        assert!(recover_journal(&journal_path).unwrap());
+        assert!(!Path::new(staged_path).exists());
+        assert!(!Path::new(&path("next")).exists());
+        assert!(!recover_journal(&journal_path).unwrap());
+
+        let (mut transition, staged_ranges) =
+            Transition::begin(journal_path.clone(), &ranges).unwrap();
+        fs::write(&staged_ranges[0].lambda_state_next_path, b"complete").unwrap();
+        let staged_snapshot = transition.stage_snapshot(&path("snapshot")).unwrap();
+        fs::create_dir(&staged_snapshot).unwrap();
+        fs::write(Path::new(&staged_snapshot).join("config"), b"stored").unwrap();
+        let mut journal = OpenOptions::new().append(true).open(&journal_path).unwrap();
+        writeln!(journal, "{COMMIT_MARKER}").unwrap();
+        let err = recover_journal(&journal_path).unwrap_err();
+        assert!(matches!(err, AdvanceError::StateBusy { .. }));
+        assert_eq!(pending_journals(&dir).unwrap(), vec![journal_path.clone()]);
+        drop(transition);
+        assert!(recover_journal(&journal_path).unwrap());
+        assert_eq!(fs::read(path("next")).unwrap(), b"complete");
+        assert_eq!(
+            fs::read(Path::new(&path("snapshot")).join("config")).unwrap(),
+            b"stored"
+        );
+        assert!(!Path::new(&staged_snapshot).exists());
+        assert!(!Path::new(&journal_path).exists());
+        assert!(pending_journals(&dir).unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}